@@ -1,17 +1,47 @@
-use std::convert::TryInto;
-
-use crate::msg::{HandleAnswer};
-use crate::state::{append_message, Message, State, PREFIX_MSGS_RECEIVED, CONFIG_KEY, load, write_viewing_key, create_empty_collection};
+use crate::msg::{HandleAnswer, LabelCount, MessageLabels, ResponseStatus, MailboxArchive, ARCHIVE_VERSION, MailboxDigestResponse};
+use crate::state::{Message, StoredMessage, State, PREFIX_MSGS_RECEIVED, read_config, write_config, write_viewing_key,
+    read_legacy_messages, has_legacy_collection, migrate_legacy_collection, migrate_on_write, write_storage_version, CURRENT_STORAGE_VERSION, create_empty_collection,
+    read_mailbox_header, write_mailbox_header, remove_last, MessageFilter, PREFIX_SENDER_INDEX, Tombstone, append_tombstone,
+    PREFIX_TOMBSTONES, PREFIX_LEGACY_TOMBSTONES, StoredTombstone, LegacyTombstone, PREFIX_LEGACY_MESSAGE_META, revoke_viewing_key, DEFAULT_VIEWING_KEY_LABEL,
+    ReadGrant, write_read_grant, remove_read_grant,
+    SendGrant, write_send_grant, remove_send_grant, read_send_grant,
+    Label, MAX_LABELS, read_labels, write_labels, MessageMeta, read_message_meta, write_message_meta, current_generation,
+    PREFIX_MESSAGE_META, MAX_PINNED, read_pinned, write_pinned, collection_home,
+    ForwardingRule, MAX_FORWARDING_RULES, read_forwarding_rules, write_forwarding_rules,
+    write_collection_home, remove_mailbox_header, move_entry, write_mailbox_transfer, read_mailbox_transfer, remove_mailbox_transfer,
+    read_viewing_keys, write_viewing_keys, MAX_VIEWING_KEYS, PREFIX_MAILBOX_SETTINGS, PREFIX_LEGACY_MAILBOX_SETTINGS, PREFIX_LABELS, PREFIX_PINNED, PREFIX_FORWARDING_RULES,
+    PREFIX_MAILBOX_TRANSFERS, MailboxHeader, import_message, PREFIX_DIGESTS, DIGEST_HEAD_KEY, EMPTY_DIGEST, chain_link, read_grantees, PREFIX_READ_GRANTS, PREFIX_SEND_GRANTS, set_closed, is_closed, remove_entry,
+    MailboxSettings, Postage, write_mailbox_settings, read_mailbox_settings, RateLimit, read_send_window, write_send_window, SendWindow,
+    PREFIX_SEND_WINDOW, PREFIX_INBOUND_WINDOW, ContractStatusLevel, remove_send_window};
 use crate::viewing_key::ViewingKey;
-use cosmwasm_std::{ to_binary, Api, Env, Extern, HandleResponse, HumanAddr, Querier, StdError, StdResult, Storage, ReadonlyStorage,
+use cosmwasm_std::{ to_binary, to_vec, Binary, Api, BankMsg, CosmosMsg, Env, Extern, HandleResponse, HumanAddr, Querier, StdError, StdResult, Storage, ReadonlyStorage,
+    Uint128,
 };
+use secret_toolkit::snip20::{register_receive_msg, transfer_msg};
 
 use cosmwasm_storage::{ReadonlyPrefixedStorage, PrefixedStorage};
-use secret_toolkit::storage::{AppendStore, AppendStoreMut};
+use secret_toolkit::storage::AppendStore;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use secret_toolkit_crypto::sha_256;
+
+//block size used to pad our responses and the messages we send to SNIP-20 contracts
+pub const BLOCK_SIZE: usize = 256;
+
+pub fn only_admin<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    env: &Env,
+) -> StdResult<State> {
+    let config = read_config(&deps.storage)?;
+    if config.owner != deps.api.canonical_address(&env.message.sender)? {
+        return Err(StdError::unauthorized());
+    }
+    Ok(config)
+}
 
 // HandleMsg::InitAddress
-/*append_message will first create an appendStore space for whoever called try_init. We save a dummy message at index 0 
-so we can easily retrieve the owner of the space and possibly handle edge cases*/
+/*create_empty_collection will first create a header for whoever called try_init. The header records the owner of the 
+space, which used to be kept in a dummy message at index 0*/
 
 pub fn try_init<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
@@ -19,23 +49,23 @@ pub fn try_init<S: Storage, A: Api, Q: Querier>(
     entropy: String,
 ) -> StdResult<HandleResponse> {
 
-    let already_init = collection_exist(&mut deps.storage, &env.message.sender);
+    let already_init = collection_exist(&deps.storage, &env.message.sender);
 
     let ha = deps.api.human_address(&deps.api.canonical_address(&env.message.sender)?)?;
 
-    let dummy_message = Message::new(String::from("Placeholder contents"), String::from(env.message.sender.as_str()));
-
     match already_init{
         false => {
-            let _storage_space = create_empty_collection(&mut deps.storage, &ha);
-            let _appending_message = append_message(&mut deps.storage, &dummy_message, &ha);
+            //reopens a mailbox that was closed
+            set_closed(&mut deps.storage, &ha, false);
+            create_empty_collection(&mut deps.storage, &ha)?;
+            let mut config = read_config(&deps.storage)?;
+            config.total_mailboxes += 1;
+            write_config(&mut deps.storage, &config)?;
 
             //create a viewing key
-            let config: State = load(&mut deps.storage, CONFIG_KEY)?;
-            let prng_seed = config.prng_seed;
-            let key = ViewingKey::new(&env, &prng_seed, (&entropy).as_ref());
+            let key = new_viewing_key(deps, &env, &entropy)?;
             let message_sender = deps.api.canonical_address(&env.message.sender)?;
-            write_viewing_key(&mut deps.storage, &message_sender, &key);
+            write_viewing_key(&mut deps.storage, &message_sender, DEFAULT_VIEWING_KEY_LABEL, &key, env.block.height)?;
             Ok(HandleResponse {
                 messages: vec![],
                 log: vec![],
@@ -43,25 +73,48 @@ pub fn try_init<S: Storage, A: Api, Q: Querier>(
             })
         }
         true => {
-            let error_message = format!("user has already been initiated!");
-            Err(StdError::generic_err(error_message))
+            Err(StdError::generic_err("user has already been initiated!"))
         }
         }
     }
     
+/*Generates a key from the current PRNG seed and replaces the seed with the next one the PRNG produced. The seed set in 
+init is only ever used for the first key, so knowing it (plus the block data) isn't enough to work out later keys*/
+fn new_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    entropy: &str,
+) -> StdResult<ViewingKey> {
+    let mut config = read_config(&deps.storage)?;
+
+    let (key, next_seed) = ViewingKey::new(env, &config.prng_seed, entropy.as_ref());
+
+    config.prng_seed = next_seed.to_vec();
+    write_config(&mut deps.storage, &config)?;
+
+    Ok(key)
+}
+
+//longest label a viewing key can have
+pub const MAX_LABEL_LENGTH: usize = 32;
+
+//creates a key under label, replacing any key the caller already has with that label and leaving their other keys alone
 pub fn try_create_viewing_key<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     entropy: String,
+    label: Option<String>,
 ) -> StdResult<HandleResponse> {
-    let config: State = load(&mut deps.storage, CONFIG_KEY)?;
-    let prng_seed = config.prng_seed;
+    let label = label.unwrap_or_else(|| DEFAULT_VIEWING_KEY_LABEL.to_string());
+    if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Err(StdError::generic_err(format!("Viewing key labels must be 1 to {} bytes long", MAX_LABEL_LENGTH)));
+    }
 
-    let key = ViewingKey::new(&env, &prng_seed, (&entropy).as_ref());
+    let key = new_viewing_key(deps, &env, &entropy)?;
 
     let message_sender = deps.api.canonical_address(&env.message.sender)?;
 
-    write_viewing_key(&mut deps.storage, &message_sender, &key);
+    write_viewing_key(&mut deps.storage, &message_sender, &label, &key, env.block.height)?;
 
     Ok(HandleResponse {
         messages: vec![],
@@ -70,30 +123,661 @@ pub fn try_create_viewing_key<S: Storage, A: Api, Q: Querier>(
     })
 }
 
+// HandleMsg::SetViewingKey
+/*SNIP-20 style flow where the client supplies the key. It is only ever stored hashed, the same as the keys we generate*/
+pub fn try_set_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    key: String,
+    label: Option<String>,
+) -> StdResult<HandleResponse> {
+    let label = label.unwrap_or_else(|| DEFAULT_VIEWING_KEY_LABEL.to_string());
+    if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Err(StdError::generic_err(format!("Viewing key labels must be 1 to {} bytes long", MAX_LABEL_LENGTH)));
+    }
+
+    let key = ViewingKey(key);
+    key.check_strength()?;
+
+    let message_sender = deps.api.canonical_address(&env.message.sender)?;
+    write_viewing_key(&mut deps.storage, &message_sender, &label, &key, env.block.height)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetViewingKey { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::GrantReadAccess
+/*Lets grantee read the caller's messages with their own viewing key, through GetMessages { viewer }. Granting again 
+replaces the earlier grant*/
+pub fn try_grant_read_access<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    grantee: HumanAddr,
+    expires_at: Option<u64>,
+    filter: Option<MessageFilter>,
+) -> StdResult<HandleResponse> {
+    if grantee == env.message.sender {
+        return Err(StdError::generic_err("You can already read your own messages"));
+    }
+
+    let grant = ReadGrant { expires_at, filter: filter.unwrap_or_default() };
+    write_read_grant(&mut deps.storage, &env.message.sender, &grantee, &grant)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::RevokeReadAccess
+pub fn try_revoke_read_access<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    grantee: HumanAddr,
+) -> StdResult<HandleResponse> {
+    remove_read_grant(&mut deps.storage, &env.message.sender, &grantee)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::AuthorizeSender
+/*Lets delegate send messages that are recorded as coming from the caller, through SendMessageAs. Authorizing again 
+replaces the earlier allowance*/
+pub fn try_authorize_sender<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    delegate: HumanAddr,
+    allowance: Option<u32>,
+) -> StdResult<HandleResponse> {
+    if delegate == env.message.sender {
+        return Err(StdError::generic_err("You can already send as yourself"));
+    }
+
+    write_send_grant(&mut deps.storage, &env.message.sender, &delegate, &SendGrant { allowance })?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::RevokeSender
+pub fn try_revoke_sender<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    delegate: HumanAddr,
+) -> StdResult<HandleResponse> {
+    remove_send_grant(&mut deps.storage, &env.message.sender, &delegate)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+//checks that delegate may send one message as from, and takes it off their allowance
+pub fn use_send_grant<S: Storage>(store: &mut S, from: &HumanAddr, delegate: &HumanAddr) -> StdResult<()> {
+    let mut grant = read_send_grant(store, from, delegate)?
+        .ok_or_else(|| StdError::generic_err(format!("{} has not authorized you to send as them", from)))?;
+
+    if let Some(allowance) = grant.allowance {
+        if allowance == 0 {
+            return Err(StdError::generic_err(format!("You have used up your allowance to send as {}", from)));
+        }
+        grant.allowance = Some(allowance - 1);
+        write_send_grant(store, from, delegate, &grant)?;
+    }
+
+    Ok(())
+}
+
+// HandleMsg::CreateLabel
+pub fn try_create_label<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    name: String,
+) -> StdResult<HandleResponse> {
+    if name.is_empty() || name.len() > MAX_LABEL_LENGTH {
+        return Err(StdError::generic_err(format!("Labels must be 1 to {} bytes long", MAX_LABEL_LENGTH)));
+    }
+
+    let mut labels = read_labels(&deps.storage, &env.message.sender)?;
+    if labels.find(&name).is_some() {
+        return Err(StdError::generic_err(format!("You already have a label called {}", name)));
+    }
+    if labels.labels.len() >= MAX_LABELS {
+        return Err(StdError::generic_err(format!("You can have at most {} labels", MAX_LABELS)));
+    }
+    labels.labels.push(Label { id: labels.next_id, name });
+    labels.next_id += 1;
+    write_labels(&mut deps.storage, &env.message.sender, &labels)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::DeleteLabel
+/*Messages keep the deleted label's id in their MessageMeta, which is simply ignored from then on. Cleaning it out of
+every message would cost gas in proportion to the size of the collection*/
+pub fn try_delete_label<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    name: String,
+) -> StdResult<HandleResponse> {
+    let mut labels = read_labels(&deps.storage, &env.message.sender)?;
+    let count = labels.labels.len();
+    labels.labels.retain(|label| label.name != name);
+    if labels.labels.len() == count {
+        return Err(StdError::generic_err(format!("You don't have a label called {}", name)));
+    }
+    write_labels(&mut deps.storage, &env.message.sender, &labels)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+//most message ids one ApplyLabel or SetArchived call can change
+pub const MAX_IDS_PER_CALL: usize = 100;
+
+//applies change to the MessageMeta of each of the caller's messages in ids, which all have to be readable
+fn update_message_meta<S: Storage, A: Api, Q: Querier, F: Fn(&mut MessageMeta)>(
+    deps: &mut Extern<S, A, Q>,
+    owner: &HumanAddr,
+    ids: &[u64],
+    change: F,
+) -> StdResult<HandleResponse> {
+    if ids.len() > MAX_IDS_PER_CALL {
+        return Err(StdError::generic_err(format!("At most {} messages at a time", MAX_IDS_PER_CALL)));
+    }
+
+    let generation = current_generation(&deps.storage, owner)?;
+    for id in ids {
+        get_message(&deps.storage, owner, *id)?;
+        let mut meta = read_message_meta(&deps.storage, owner, generation, *id)?;
+        change(&mut meta);
+        write_message_meta(&mut deps.storage, owner, generation, *id, &meta)?;
+    }
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::ApplyLabel
+pub fn try_apply_label<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ids: Vec<u64>,
+    label: String,
+    remove: bool,
+) -> StdResult<HandleResponse> {
+    let labels = read_labels(&deps.storage, &env.message.sender)?;
+    let label_id = labels.find(&label)
+        .ok_or_else(|| StdError::generic_err(format!("You don't have a label called {}", label)))?
+        .id;
+
+    update_message_meta(deps, &env.message.sender, &ids, |meta| {
+        meta.labels.retain(|id| *id != label_id);
+        if !remove {
+            meta.labels.push(label_id);
+        }
+    })
+}
+
+// HandleMsg::SetArchived
+pub fn try_set_archived<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    ids: Vec<u64>,
+    archived: bool,
+) -> StdResult<HandleResponse> {
+    update_message_meta(deps, &env.message.sender, &ids, |meta| meta.archived = archived)
+}
+
+// HandleMsg::Star
+pub fn try_star<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    id: u64,
+    starred: bool,
+) -> StdResult<HandleResponse> {
+    update_message_meta(deps, &env.message.sender, &[id], |meta| meta.starred = starred)
+}
+
+// HandleMsg::Pin
+/*Pinning copies the message into the caller's pinned list, which DeleteAllMessages leaves alone unless asked not to.
+Unpinning only drops the copy, so a message that was deleted in the meantime is gone for good*/
+pub fn try_pin<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    id: u64,
+    pinned: bool,
+) -> StdResult<HandleResponse> {
+    let mut pins = read_pinned(&deps.storage, &env.message.sender)?;
+    let is_pinned = pins.iter().any(|message| message.get_id() == id);
+
+    if pinned && !is_pinned {
+        let message = get_message(&deps.storage, &env.message.sender, id)?;
+        if pins.len() >= MAX_PINNED {
+            return Err(StdError::generic_err(format!("You can have at most {} pinned messages", MAX_PINNED)));
+        }
+        pins.push(message);
+    } else if !pinned {
+        if !is_pinned {
+            return Err(StdError::generic_err(format!("Message {} is not pinned", id)));
+        }
+        pins.retain(|message| message.get_id() != id);
+    }
+    write_pinned(&mut deps.storage, &env.message.sender, &pins)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::RevokeViewingKey
+pub fn try_revoke_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    label: String,
+) -> StdResult<HandleResponse> {
+    let message_sender = deps.api.canonical_address(&env.message.sender)?;
+
+    if !revoke_viewing_key(&mut deps.storage, &message_sender, &label)? {
+        return Err(StdError::generic_err(format!("You have no viewing key labelled {}", label)));
+    }
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::SetMailboxSettings
+/*settings are stored under the caller's own address, the same way their collection is. If the caller asks to be paid
+in a SNIP-20 token, we register this contract with that token so its Receive callback can deliver paid messages*/
+pub fn try_set_mailbox_settings<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    settings: MailboxSettings,
+) -> StdResult<HandleResponse> {
+
+    let mut messages = vec![];
+    if let Some(Postage::Snip20 { contract, code_hash, .. }) = &settings.postage {
+        messages.push(register_receive_msg(
+            env.contract_code_hash.clone(),
+            None,
+            BLOCK_SIZE,
+            code_hash.clone(),
+            contract.clone(),
+        )?);
+    }
+
+    write_mailbox_settings(&mut deps.storage, &env.message.sender, &settings)?;
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::SetForwardingRule
+//one rule per sender, so setting a rule for a sender replaces the old one. No forward_to removes it
+pub fn try_set_forwarding_rule<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    match_sender: HumanAddr,
+    forward_to: Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    let mut rules = read_forwarding_rules(&deps.storage, &env.message.sender)?;
+    rules.retain(|rule| rule.match_sender != match_sender);
+
+    if let Some(forward_to) = forward_to {
+        if forward_to == env.message.sender {
+            return Err(StdError::generic_err("You can't forward messages to yourself"));
+        }
+        if rules.len() >= MAX_FORWARDING_RULES {
+            return Err(StdError::generic_err(format!("You can have at most {} forwarding rules", MAX_FORWARDING_RULES)));
+        }
+        rules.push(ForwardingRule { match_sender, forward_to });
+    }
+    write_forwarding_rules(&mut deps.storage, &env.message.sender, &rules)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::ProposeMailboxTransfer
+//proposing again replaces the earlier proposal. Nothing moves until new_owner accepts
+pub fn try_propose_mailbox_transfer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    new_owner: HumanAddr,
+) -> StdResult<HandleResponse> {
+    if read_mailbox_header(&deps.storage, &env.message.sender)?.is_none() {
+        return Err(StdError::generic_err("You do not have a collection yet, or it is waiting to be migrated"));
+    }
+    if is_closed(&deps.storage, &env.message.sender) {
+        return Err(StdError::generic_err("Your mailbox is being closed"));
+    }
+    if new_owner == env.message.sender {
+        return Err(StdError::generic_err("You already own your mailbox"));
+    }
+    write_mailbox_transfer(&mut deps.storage, &env.message.sender, &new_owner)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::AcceptMailboxTransfer
+/*Hands from's mailbox over to the caller. Messages stay where they are: the caller's home is pointed at the collection
+and from gets a fresh, empty home, so the transfer costs the same however many messages there are. The header, 
+settings, labels, pins, forwarding rules and viewing keys move along. Read and send grants stay with from, since they
+can't be listed*/
+pub fn try_accept_mailbox_transfer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let new_owner = env.message.sender;
+    if read_mailbox_transfer(&deps.storage, &from)?.as_ref() != Some(&new_owner) {
+        return Err(StdError::generic_err(format!("{} has not proposed to transfer their mailbox to you", from)));
+    }
+    if collection_exist(&deps.storage, &new_owner) {
+        return Err(StdError::generic_err("You already have a collection"));
+    }
+    //a mailbox being closed has already lost its settings and keys
+    if is_closed(&deps.storage, &from) {
+        return Err(StdError::generic_err(format!("{} is closing their mailbox", from)));
+    }
+    let mut header = read_mailbox_header(&deps.storage, &from)?
+        .ok_or_else(|| StdError::generic_err(format!("{} no longer has a collection", from)))?;
+
+    let old_keys = read_viewing_keys(&deps.storage, &deps.api.canonical_address(&from)?)?;
+    let new_canonical = deps.api.canonical_address(&new_owner)?;
+    let mut keys = read_viewing_keys(&deps.storage, &new_canonical)?;
+    for record in old_keys {
+        if !keys.iter().any(|key| key.label == record.label) {
+            keys.push(record);
+        }
+    }
+    if keys.len() > MAX_VIEWING_KEYS {
+        return Err(StdError::generic_err(format!(
+            "You would end up with more than {} viewing keys. Revoke some first", MAX_VIEWING_KEYS
+        )));
+    }
+    write_viewing_keys(&mut deps.storage, &new_canonical, &keys)?;
+    write_viewing_keys(&mut deps.storage, &deps.api.canonical_address(&from)?, &[])?;
+
+    //the transfer takes a sequence number too, which also makes the fresh home below a name no collection used before
+    header.owner = new_owner.to_string();
+    header.sequence += 1;
+    let home = collection_home(&deps.storage, &from)?;
+    write_mailbox_header(&mut deps.storage, &new_owner, &header)?;
+    write_collection_home(&mut deps.storage, &new_owner, &home)?;
+    remove_mailbox_header(&mut deps.storage, &from);
+    write_collection_home(&mut deps.storage, &from, &format!("{}#{}", home, header.sequence))?;
+    //taking over a collection reopens a mailbox new_owner closed before, just like InitAddress would
+    set_closed(&mut deps.storage, &new_owner, false);
+
+    for prefix in &[PREFIX_MAILBOX_SETTINGS, PREFIX_LEGACY_MAILBOX_SETTINGS, PREFIX_LABELS, PREFIX_PINNED, PREFIX_FORWARDING_RULES] {
+        move_entry(&mut deps.storage, prefix, &from, &new_owner);
+    }
+    remove_mailbox_transfer(&mut deps.storage, &from);
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+/*Applies recipient's forwarding rules to a message that was just delivered to mailbox. Copies are appended straight to
+the target's collection rather than delivered, so the target's own rules never fire on them and no chain of rules can
+loop. Targets that wouldn't take a message from recipient, or charge postage, are skipped rather than failing the
+delivery. Returns how many copies were made and how many of them started a new collection*/
+pub fn forward_by_rules<S: Storage>(
+    storage: &mut S,
+    recipient: &HumanAddr,
+    mailbox: &HumanAddr,
+    message: &Message,
+    env: &Env,
+) -> StdResult<(u64, u64)> {
+    let (mut copies, mut created) = (0, 0);
+    for rule in read_forwarding_rules(storage, recipient)? {
+        if rule.match_sender.0 != message.get_owner() || &rule.forward_to == mailbox {
+            continue;
+        }
+        if !accepts_forward(storage, env.block.height, recipient, &rule.forward_to)? {
+            continue;
+        }
+        let copy = message.forwarded(recipient, message.get_note().map(String::from), env.block.time);
+        let (_, new_collection) = copy.store_message(storage, &rule.forward_to)?;
+        copies += 1;
+        created += new_collection as u64;
+    }
+    Ok((copies, created))
+}
+
+/*Whether target takes a message passed on by from. Nobody pays postage for a message passed on, so a target that would 
+charge from is skipped, as is one that doesn't accept from from, has reached its inbound_limit for them, is still in the 
+old layout or is being closed. Counts against the target's inbound_limit like a SendMessage from from would*/
+pub fn accepts_forward<S: Storage>(
+    storage: &mut S,
+    height: u64,
+    from: &HumanAddr,
+    target: &HumanAddr,
+) -> StdResult<bool> {
+    let settings = read_mailbox_settings(storage, target)?;
+    let paid = settings.postage.is_some() && !settings.is_allowed(from);
+    if !settings.accepts_from(from) || paid || has_legacy_collection(storage, target) || is_closed(storage, target) {
+        return Ok(false);
+    }
+    match &settings.inbound_limit {
+        Some(limit) if !settings.is_allowed(from) =>
+            consume_window(storage, &[PREFIX_INBOUND_WINDOW, target.0.as_bytes(), from.0.as_bytes()], limit, height),
+        _ => Ok(true),
+    }
+}
+
+/*Checks the native coins attached to a SendMessage against the recipient's postage and builds the message that forwards
+them to the recipient. Senders on the allow list skip the fee, but anything they attach is still forwarded so it never
+gets stuck in the contract*/
+pub fn collect_native_postage(
+    env: &Env,
+    sender: &HumanAddr,
+    to: &HumanAddr,
+    settings: &MailboxSettings,
+) -> StdResult<Vec<CosmosMsg>> {
+
+    if !settings.is_allowed(sender) {
+        match &settings.postage {
+            Some(Postage::Native { denom, amount }) => {
+                let paid = env.message.sent_funds
+                    .iter()
+                    .filter(|coin| &coin.denom == denom)
+                    .fold(0u128, |total, coin| total + coin.amount.u128());
+                if paid < amount.u128() {
+                    return Err(StdError::generic_err(format!(
+                        "{} requires postage of {}{} per message", to, amount, denom
+                    )));
+                }
+            }
+            Some(Postage::Snip20 { .. }) => {
+                return Err(StdError::generic_err(format!(
+                    "{} requires postage paid in a SNIP-20 token. Send the tokens to this contract with a SendMessage msg", to
+                )));
+            }
+            None => {}
+        }
+    }
+
+    if env.message.sent_funds.is_empty() {
+        return Ok(vec![]);
+    }
+
+    Ok(vec![CosmosMsg::Bank(BankMsg::Send {
+        from_address: env.contract.address.clone(),
+        to_address: to.clone(),
+        amount: env.message.sent_funds.clone(),
+    })])
+}
+
+/*Same as above for postage paid through a SNIP-20 Receive callback. env.message.sender is the token contract here,
+so we make sure it is the token the recipient actually asked for before forwarding the amount*/
+pub fn collect_snip20_postage(
+    env: &Env,
+    sender: &HumanAddr,
+    to: &HumanAddr,
+    amount: Uint128,
+    settings: &MailboxSettings,
+) -> StdResult<Vec<CosmosMsg>> {
+
+    match &settings.postage {
+        Some(Postage::Snip20 { contract, code_hash, amount: price }) if contract == &env.message.sender => {
+            if amount.u128() < price.u128() && !settings.is_allowed(sender) {
+                return Err(StdError::generic_err(format!(
+                    "{} requires postage of {} tokens per message", to, price
+                )));
+            }
+            Ok(vec![transfer_msg(
+                to.clone(),
+                amount,
+                None,
+                None,
+                BLOCK_SIZE,
+                code_hash.clone(),
+                contract.clone(),
+            )?])
+        }
+        _ => Err(StdError::generic_err(format!("{} does not accept postage in this token", to))),
+    }
+}
+
+// HandleMsg::SetSendLimit
+pub fn try_set_send_limit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    limit: Option<RateLimit>,
+) -> StdResult<HandleResponse> {
+
+    let mut config = only_admin(deps, &env)?;
+    config.send_limit = limit;
+    write_config(&mut deps.storage, &config)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+// HandleMsg::SetContractStatus
+pub fn try_set_contract_status<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    level: ContractStatusLevel,
+) -> StdResult<HandleResponse> {
+
+    let mut config = only_admin(deps, &env)?;
+    config.status = level;
+    write_config(&mut deps.storage, &config)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?),
+    })
+}
+
+/*Counts one more message against the window stored under namespace. The window opens with the first message a sender
+sends and closes limit.window blocks later, after which counting starts again from the current height*/
+fn consume_window<S: Storage>(
+    store: &mut S,
+    namespace: &[&[u8]],
+    limit: &RateLimit,
+    height: u64,
+) -> StdResult<bool> {
+
+    let mut window = read_send_window(store, namespace)?;
+    if window.count == 0 || height >= window.start.saturating_add(limit.window) {
+        window = SendWindow { start: height, count: 0 };
+    }
+    if window.count >= limit.max_messages {
+        return Ok(false);
+    }
+    window.count += 1;
+    write_send_window(store, namespace, &window)?;
+    Ok(true)
+}
+
+//enforces both the contract-wide send limit and the recipient's own inbound limit for this sender
+pub fn check_rate_limits<S: Storage>(
+    store: &mut S,
+    env: &Env,
+    sender: &HumanAddr,
+    to: &HumanAddr,
+    settings: &MailboxSettings,
+) -> StdResult<()> {
+
+    let config = read_config(store)?;
+    if let Some(limit) = &config.send_limit {
+        if !consume_window(store, &[PREFIX_SEND_WINDOW, sender.0.as_bytes()], limit, env.block.height)? {
+            return Err(StdError::generic_err(format!(
+                "Send limit reached: at most {} messages every {} blocks", limit.max_messages, limit.window
+            )));
+        }
+    }
+
+    if let Some(limit) = &settings.inbound_limit {
+        if !settings.is_allowed(sender)
+            && !consume_window(store, &[PREFIX_INBOUND_WINDOW, to.0.as_bytes(), sender.0.as_bytes()], limit, env.block.height)? {
+            return Err(StdError::generic_err(format!(
+                "{} accepts at most {} messages from you every {} blocks", to, limit.max_messages, limit.window
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 //using store.is_empty() was also another attempted approach - might need it in future for something else. 
-pub fn collection_exist<'a, S: Storage>(
-    store: &'a mut S,
+//a collection exists as soon as it has a header, even if its current generation is empty
+pub fn collection_exist<S: ReadonlyStorage>(
+    store: &S,
     for_address: &HumanAddr,
     
 ) -> bool{
 
-    let store = ReadonlyPrefixedStorage::multilevel(
-        &[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes()],
-        store
-    );
-
-    // Try to access the storage of files for the account.
-    let store = AppendStore::<Message, _, _>::attach(&store);
-    let store = if let Some(result) = store {
-        result
-    } else {
-        return false
-    };
-
-    match store {
-        Ok(_v) => {return true},
-        Err(_e) => return false,
-    };
+    matches!(read_mailbox_header(store, for_address), Ok(Some(_))) || has_legacy_collection(store, for_address)
 }
 
 pub fn get_collection_owner<S: ReadonlyStorage>(
@@ -101,16 +785,10 @@ pub fn get_collection_owner<S: ReadonlyStorage>(
     behalf: &HumanAddr,
 ) -> StdResult<String> {
     
-    let option_error_message = format!("Provided storage doesn't seem like an AppendStore");            
-    let mut store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, behalf.0.as_bytes()], storage);
-    let store = AppendStore::<Message, _, _>::attach(&mut store).unwrap_or(Err(StdError::generic_err(option_error_message)))?;
+    let header = read_mailbox_header(storage, behalf)?
+        .ok_or_else(|| StdError::generic_err(format!("{} does not have a collection yet", behalf)))?;
 
-    //retrieve message at index 0 which holds the owner of the collection
-    let message = store.get_at(0)?;
-    let owner = message.get_owner();
-    //let owner = HumanAddr::from(owner);
-
-    Ok(String::from(owner))
+    Ok(header.owner)
     
 }
 
@@ -119,84 +797,759 @@ pub fn get_messages<S: ReadonlyStorage>(
     behalf: &HumanAddr,
 
 ) -> StdResult<Vec<Message>> {
+    get_filtered_messages(storage, behalf, &MessageFilter::default())
+}
+
+/*Messages are appended in delivery order, so their timestamps never decrease. That lets us binary search for the
+since/until bounds instead of reading the whole collection. With from_sender we search that sender's index instead, 
+which only holds the positions of their messages*/
+pub fn get_filtered_messages<S: ReadonlyStorage>(
+    storage: &S,
+    behalf: &HumanAddr,
+    filter: &MessageFilter,
+) -> StdResult<Vec<Message>> {
+    // If the collection doesn't exist yet, return an empty collection.
+    let header = match read_mailbox_header(storage, behalf)? {
+        Some(header) => header,
+        //a collection that hasn't been migrated yet is small enough to have been read whole all along
+        None => return Ok(read_legacy_messages(storage, behalf)?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|message| filter.matches(message))
+            .collect()),
+    };
+    let home = collection_home(storage, behalf)?;
+    let generation = header.generation.to_be_bytes();
+
     let store = ReadonlyPrefixedStorage::multilevel(
-        &[PREFIX_MSGS_RECEIVED, behalf.0.as_bytes()],
+        &[PREFIX_MSGS_RECEIVED, home.as_bytes(), &generation],
         storage
     );
 
-    // Try to access the collection for the account.
-    // If it doesn't exist yet, return an empty collection.
-    let store = AppendStore::<Message, _, _>::attach(&store);
+    // The current generation has no AppendStore until something is delivered to it
+    let store = AppendStore::<StoredMessage, _, _>::attach(&store);
     let store = if let Some(result) = store {
         result?
     } else {
         return Ok(vec![]);
     };
-    
-    let tx_iter = store
-        .iter()
-        .take(store.len().try_into().unwrap());
+    let first = first_retained(storage, behalf, store.len())?;
+
+    match &filter.from_sender {
+        Some(sender) => {
+            let index = ReadonlyPrefixedStorage::multilevel(
+                &[PREFIX_SENDER_INDEX, home.as_bytes(), &generation, sender.0.as_bytes()],
+                storage
+            );
+            let index = match AppendStore::<u32, _, _>::attach(&index) {
+                Some(result) => result?,
+                None => return Ok(vec![]),
+            };
+            let retained = partition_point(index.len(), |i| Ok(index.get_at(i)? < first))?;
+            collect_in_range(retained, index.len(), |i| store.get_at(index.get_at(i)?).map(Message::from), filter)
+        }
+        None => collect_in_range(first, store.len(), |i| store.get_at(i).map(Message::from), filter),
+    }
+}
+
+/*Position of the oldest message the owner's retention setting still lets through, in a generation of len messages. 
+Older messages stay in storage until the next DeleteAllMessages, they just can't be read anymore*/
+//id of the oldest message stored in generation of for_address's collection, whether the retention setting hides it or not
+fn first_stored_id<S: ReadonlyStorage>(storage: &S, for_address: &HumanAddr, generation: u32) -> StdResult<Option<u64>> {
+    let home = collection_home(storage, for_address)?;
+    let store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, home.as_bytes(), &generation.to_be_bytes()], storage);
+    match AppendStore::<StoredMessage, _, _>::attach(&store) {
+        Some(store) => {
+            let store = store?;
+            match store.is_empty() {
+                true => Ok(None),
+                false => Ok(Some(Message::from(store.get_at(0)?).get_id())),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn first_retained<S: ReadonlyStorage>(storage: &S, for_address: &HumanAddr, len: u32) -> StdResult<u32> {
+    let settings = read_mailbox_settings(storage, for_address)?;
+    Ok(settings.retention.map_or(0, |retention| len.saturating_sub(retention)))
+}
+
+/*get_filtered_messages plus what the owner did with each message, with pinned messages first. The label and archived
+parts of filter are applied here, and counts tell how many of the messages the rest of the filter matched carry each
+label. Pinned messages aren't subject to the retention setting*/
+pub fn get_labelled_messages<S: ReadonlyStorage>(
+    storage: &S,
+    behalf: &HumanAddr,
+    filter: &MessageFilter,
+) -> StdResult<(Vec<Message>, Vec<MessageLabels>, Vec<LabelCount>)> {
+    let labels = read_labels(storage, behalf)?;
+    let wanted_label = match &filter.label {
+        Some(name) => Some(labels.find(name).ok_or_else(|| StdError::generic_err(format!("No label called {}", name)))?.id),
+        None => None,
+    };
+    let generation = current_generation(storage, behalf)?;
+
+    let mut counts: Vec<LabelCount> = labels.labels.iter().map(|label| LabelCount { label: label.name.clone(), count: 0 }).collect();
+    let mut messages = vec![];
+    let mut message_labels = vec![];
+
+    for (message, pinned) in get_pinned_first(storage, behalf, filter)? {
+        let meta = read_message_meta(storage, behalf, generation, message.get_id())?;
+        if filter.archived.map_or(false, |archived| archived != meta.archived) {
+            continue;
+        }
+        let names: Vec<String> = meta.labels.iter().filter_map(|id| labels.name_of(*id)).map(String::from).collect();
+        for name in &names {
+            if let Some(count) = counts.iter_mut().find(|count| &count.label == name) {
+                count.count += 1;
+            }
+        }
+        if wanted_label.map_or(false, |wanted| !meta.labels.contains(&wanted)) {
+            continue;
+        }
+        if !names.is_empty() || meta.archived || meta.starred || pinned {
+            message_labels.push(MessageLabels {
+                id: message.get_id(),
+                labels: names,
+                archived: meta.archived,
+                starred: meta.starred,
+                pinned,
+            });
+        }
+        messages.push(message);
+    }
 
-    let txs: StdResult<Vec<Message>> = tx_iter
-        .map(|tx| tx)
+    Ok((messages, message_labels, counts))
+}
+
+//the caller's pinned messages matching filter, then the rest of get_filtered_messages, each with whether it is pinned
+fn get_pinned_first<S: ReadonlyStorage>(
+    storage: &S,
+    behalf: &HumanAddr,
+    filter: &MessageFilter,
+) -> StdResult<Vec<(Message, bool)>> {
+    let pinned = read_pinned(storage, behalf)?;
+    let pinned_ids: Vec<u64> = pinned.iter().map(Message::get_id).collect();
+
+    let mut messages: Vec<(Message, bool)> = pinned
+        .into_iter()
+        .filter(|message| filter.matches(message))
+        .map(|message| (message, true))
         .collect();
-        txs.map(|txs| (txs)) 
+    messages.extend(get_filtered_messages(storage, behalf, filter)?
+        .into_iter()
+        .filter(|message| !pinned_ids.contains(&message.get_id()))
+        .map(|message| (message, false)));
+
+    Ok(messages)
 }
 
-//retrieve message given position of message in collection--would have to call
-//get_messages first. 
-//Could also possibly retrieve message given contents but would be a bit harder and it would mean that every contents
-//string is unique 
-//does front end need this or can front end create a function that 
-//retrieves a single message from the vector that's returned by get_messages? 
+//the caller's starred messages, pinned ones first
+pub fn get_starred_messages<S: ReadonlyStorage>(
+    storage: &S,
+    behalf: &HumanAddr,
+) -> StdResult<Vec<Message>> {
+    let generation = current_generation(storage, behalf)?;
+    let mut starred = vec![];
+    for (message, _) in get_pinned_first(storage, behalf, &MessageFilter::default())? {
+        if read_message_meta(storage, behalf, generation, message.get_id())?.starred {
+            starred.push(message);
+        }
+    }
+    Ok(starred)
+}
+
+//first position in 0..len for which before returns false, assuming it is true for a prefix of the range and false after
+fn partition_point<F: Fn(u32) -> StdResult<bool>>(len: u32, before: F) -> StdResult<u32> {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if before(mid)? {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+//positions below first are skipped whatever the filter says
+fn collect_in_range<F: Fn(u32) -> StdResult<Message>>(
+    first: u32,
+    len: u32,
+    get: F,
+    filter: &MessageFilter,
+) -> StdResult<Vec<Message>> {
+    let start = match filter.since {
+        Some(since) => partition_point(len, |i| Ok(get(i)?.get_timestamp() < since))?.max(first),
+        None => first,
+    };
+    let end = match filter.until {
+        Some(until) => partition_point(len, |i| Ok(get(i)?.get_timestamp() < until))?,
+        None => len,
+    };
+
+    (start..end.max(start)).map(get).collect()
+}
+
+/*Everything that happened to a collection after sequence number cursor: the messages appended since, and tombstones
+for the deletions since. Ids and tombstone sequence numbers both only ever grow, so both are found by binary search.
+Messages the retention setting cut off are deleted too but never got a tombstone, so one covering them is made up at
+the end. Also returns the collection's latest sequence number, which is the cursor to pass next time*/
+pub fn get_messages_since<S: ReadonlyStorage>(
+    storage: &S,
+    behalf: &HumanAddr,
+    cursor: u64,
+) -> StdResult<(Vec<Message>, Vec<Tombstone>, u64)> {
+    let header = match read_mailbox_header(storage, behalf)? {
+        Some(header) => header,
+        None => {
+            //legacy messages have their position as their id, and the original layout never deleted anything
+            let messages = read_legacy_messages(storage, behalf)?.unwrap_or_default();
+            let latest = messages.last().map_or(cursor, |message| message.get_id().max(cursor));
+            let messages = messages.into_iter().filter(|message| message.get_id() > cursor).collect();
+            return Ok((messages, vec![], latest));
+        }
+    };
+
+    let home = collection_home(storage, behalf)?;
+    let store = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_MSGS_RECEIVED, home.as_bytes(), &header.generation.to_be_bytes()],
+        storage
+    );
+    let mut cut_through = None;
+    let messages = match AppendStore::<StoredMessage, _, _>::attach(&store) {
+        Some(store) => {
+            let store = store?;
+            let get = |i| store.get_at(i).map(Message::from);
+            let first = first_retained(storage, behalf, store.len())?;
+            if first > 0 {
+                cut_through = Some(get(first - 1)?.get_id());
+            }
+            let start = partition_point(store.len(), |i| Ok(get(i)?.get_id() <= cursor))?.max(first);
+            (start..store.len()).map(get).collect::<StdResult<Vec<Message>>>()?
+        }
+        None => vec![],
+    };
+
+    //the legacy tombstones are all older than the versioned ones
+    let mut tombstones = tombstones_since::<LegacyTombstone, _>(storage, PREFIX_LEGACY_TOMBSTONES, &home, cursor)?;
+    tombstones.extend(tombstones_since::<StoredTombstone, _>(storage, PREFIX_TOMBSTONES, &home, cursor)?);
+    //a client starting from 0 has nothing cached to drop. Retention spares pinned messages, so the tombstone does as well
+    if let (Some(deleted_through), true) = (cut_through, cursor > 0) {
+        let except = read_pinned(storage, behalf)?.iter().map(Message::get_id).collect();
+        tombstones.push(Tombstone { seq: header.sequence, deleted_through, except });
+    }
+
+    Ok((messages, tombstones, header.sequence))
+}
+
+//the tombstones stored as T under [prefix, home] with a seq above cursor
+fn tombstones_since<T: Serialize + DeserializeOwned + Into<Tombstone>, S: ReadonlyStorage>(
+    storage: &S,
+    prefix: &[u8],
+    home: &str,
+    cursor: u64,
+) -> StdResult<Vec<Tombstone>> {
+    let store = ReadonlyPrefixedStorage::multilevel(&[prefix, home.as_bytes()], storage);
+    match AppendStore::<T, _, _>::attach(&store) {
+        Some(store) => {
+            let store = store?;
+            let get = |i| -> StdResult<Tombstone> { Ok(store.get_at(i)?.into()) };
+            let start = partition_point(store.len(), |i| Ok(get(i)?.seq <= cursor))?;
+            (start..store.len()).map(get).collect()
+        }
+        None => Ok(vec![]),
+    }
+}
 
 //According to Erin, file sharing will be taken care of by JACKAL-storage and frontend, and this system is simply for notifying 
-//users that a file has been shared with them--so getting a single message is useless? 
+//users that a file has been shared with them. Frontends still want to open the one notification a deep link points at though
 
+/*Retrieves a message by its id. Ids only ever grow within a collection, so this is a binary search of the current
+generation. Messages removed by DeleteAllMessages are not found, unless they were pinned*/
 pub fn get_message<S: ReadonlyStorage>(
     storage: &S,
     for_address: &HumanAddr,
-    position: u32
+    id: u64,
 ) -> StdResult<Message> {
 
+    let header = match read_mailbox_header(storage, for_address)? {
+        Some(header) => header,
+        None => {
+            let messages = read_legacy_messages(storage, for_address)?
+                .ok_or_else(|| StdError::not_found(format!("collection of {}", for_address)))?;
+            return messages
+                .into_iter()
+                .find(|message| message.get_id() == id)
+                .ok_or_else(|| StdError::not_found(format!("message {}", id)));
+        }
+    };
+
+    let home = collection_home(storage, for_address)?;
     let store = ReadonlyPrefixedStorage::multilevel(
-        &[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes()],
+        &[PREFIX_MSGS_RECEIVED, home.as_bytes(), &header.generation.to_be_bytes()],
         storage
     );
 
-    // Try to access the storage of files for the account.
-    // If it doesn't exist yet, return a Message with path called "Does Not Exist" 
-    let store = AppendStore::<Message, _, _>::attach(&store);
+    if let Some(store) = AppendStore::<StoredMessage, _, _>::attach(&store) {
+        let store = store?;
+        let get = |i| store.get_at(i).map(Message::from);
+        let first = first_retained(storage, for_address, store.len())?;
 
-    let store = if let Some(result) = store {
-        result?
-    } else {
-        return Ok(Message::new(String::from("Does Not Exist/"), String::from("None")))
-    };
+        let position = partition_point(store.len(), |i| Ok(get(i)?.get_id() < id))?;
+        if position >= first && position < store.len() {
+            let message = get(position)?;
+            if message.get_id() == id {
+                return Ok(message);
+            }
+        }
+    }
 
-    store.get_at(position)
+    read_pinned(storage, for_address)?
+        .into_iter()
+        .find(|message| message.get_id() == id)
+        .ok_or_else(|| StdError::not_found(format!("message {}", id)))
 } 
 
+/*Where the hash chain of for_address's current generation stands after the message with id through, or the last one.
+A client holding the readable messages up to there folds them into base and should end up with digest. base is where
+the chain stood before the oldest message the retention setting still lets through. A collection that hasn't been
+migrated yet gets its chain worked out here*/
+pub fn get_mailbox_digest<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    through: Option<u64>,
+) -> StdResult<MailboxDigestResponse> {
+    let empty = MailboxDigestResponse {
+        digest: Binary(EMPTY_DIGEST.to_vec()),
+        base: Binary(EMPTY_DIGEST.to_vec()),
+        count: 0,
+        first_id: None,
+        last_id: None,
+    };
+
+    let header = match read_mailbox_header(storage, for_address)? {
+        Some(header) => header,
+        None => {
+            let messages: Vec<Message> = read_legacy_messages(storage, for_address)?
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|message| through.map_or(true, |through| message.get_id() <= through))
+                .collect();
+            let mut digest = EMPTY_DIGEST;
+            for message in &messages {
+                digest = chain_link(&digest, message)?;
+            }
+            return Ok(MailboxDigestResponse {
+                digest: Binary(digest.to_vec()),
+                count: messages.len() as u32,
+                first_id: messages.first().map(Message::get_id),
+                last_id: messages.last().map(Message::get_id),
+                ..empty
+            });
+        }
+    };
+
+    let home = collection_home(storage, for_address)?;
+    let generation = header.generation.to_be_bytes();
+    let messages = ReadonlyPrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, home.as_bytes(), &generation], storage);
+    let messages = match AppendStore::<StoredMessage, _, _>::attach(&messages) {
+        Some(messages) => messages?,
+        None => return Ok(empty),
+    };
+    let chain = ReadonlyPrefixedStorage::multilevel(&[PREFIX_DIGESTS, home.as_bytes(), &generation], storage);
+    let chain = match AppendStore::<[u8; 32], _, _>::attach(&chain) {
+        Some(chain) => chain?,
+        None => return Ok(empty),
+    };
+
+    let get = |i| messages.get_at(i).map(Message::from);
+    let first = first_retained(storage, for_address, messages.len())?;
+    let end = match through {
+        Some(through) => partition_point(messages.len(), |i| Ok(get(i)?.get_id() <= through))?,
+        None => messages.len(),
+    };
+    let link = |position: u32| -> StdResult<Binary> {
+        match position {
+            0 => Ok(Binary(EMPTY_DIGEST.to_vec())),
+            position => Ok(Binary(chain.get_at(position - 1)?.to_vec())),
+        }
+    };
+
+    let base = link(first)?;
+    if end <= first {
+        return Ok(MailboxDigestResponse { digest: base.clone(), base, ..empty });
+    }
+    Ok(MailboxDigestResponse {
+        digest: link(end)?,
+        base,
+        count: end - first,
+        first_id: Some(get(first)?.get_id()),
+        last_id: Some(get(end - 1)?.get_id()),
+    })
+}
+
+//most messages one page of ExportMailbox holds
+pub const MAX_EXPORT_PAGE: u32 = 100;
+
+/*One page of for_address's mailbox: up to limit of the messages they can read, oldest first, starting at the one with
+id start. Pages go by id rather than position, since the position of a message among the readable ones shifts when the
+retention setting lets older ones go. Settings and pinned messages come with the first page*/
+pub fn export_mailbox<S: ReadonlyStorage>(
+    storage: &S,
+    for_address: &HumanAddr,
+    start: u64,
+    limit: u32,
+) -> StdResult<MailboxArchive> {
+    let limit = limit.min(MAX_EXPORT_PAGE);
+
+    let (messages, total, next) = match read_mailbox_header(storage, for_address)? {
+        Some(header) => {
+            let home = collection_home(storage, for_address)?;
+            let store = ReadonlyPrefixedStorage::multilevel(
+                &[PREFIX_MSGS_RECEIVED, home.as_bytes(), &header.generation.to_be_bytes()],
+                storage
+            );
+            match AppendStore::<StoredMessage, _, _>::attach(&store) {
+                Some(store) => {
+                    let store = store?;
+                    let get = |i| store.get_at(i).map(Message::from);
+                    let first = first_retained(storage, for_address, store.len())?;
+                    let from = partition_point(store.len(), |i| Ok(get(i)?.get_id() < start))?.max(first);
+                    let to = from.saturating_add(limit).min(store.len());
+                    let messages = (from..to).map(get).collect::<StdResult<Vec<Message>>>()?;
+                    let next = if to < store.len() { Some(get(to)?.get_id()) } else { None };
+                    (messages, store.len() - first, next)
+                }
+                None => (vec![], 0, None),
+            }
+        }
+        None => {
+            let messages = read_legacy_messages(storage, for_address)?.unwrap_or_default();
+            let total = messages.len() as u32;
+            let mut rest = messages.into_iter().filter(|message| message.get_id() >= start);
+            let page: Vec<Message> = rest.by_ref().take(limit as usize).collect();
+            (page, total, rest.next().map(|message| message.get_id()))
+        }
+    };
+
+    let first_page = start == 0;
+    Ok(MailboxArchive {
+        version: ARCHIVE_VERSION,
+        owner: for_address.clone(),
+        settings: if first_page { Some(read_mailbox_settings(storage, for_address)?) } else { None },
+        pinned: if first_page { read_pinned(storage, for_address)? } else { vec![] },
+        messages,
+        start,
+        total,
+        next,
+    })
+}
+
+/*the checksum ExportMailbox hands out with an archive and ImportMailbox checks it against. It isn't keyed, so it only
+catches pages that got corrupted or cut short on the way, not where they came from. The admin has to trust the source*/
+pub fn archive_digest(archive: &MailboxArchive) -> StdResult<Binary> {
+    Ok(Binary(sha_256(&to_vec(archive)?).to_vec()))
+}
+
+// HandleMsg::ImportMailbox
+/*Admin only, for moving mailboxes between deployments. Appends one page of an ExportMailbox archive to the collection
+of its owner, keeping message ids, senders and timestamps. Pages have to be imported in order. The first page also
+brings the owner's settings and pinned messages. Viewing keys aren't part of an archive, the owner makes new ones*/
+pub fn try_import_mailbox<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    archive: MailboxArchive,
+    digest: Binary,
+) -> StdResult<HandleResponse> {
+    let mut config = only_admin(deps, &env)?;
+
+    if archive.version != ARCHIVE_VERSION {
+        return Err(StdError::generic_err(format!("Unsupported archive version {}", archive.version)));
+    }
+    if archive_digest(&archive)? != digest {
+        return Err(StdError::generic_err("The archive does not match its digest"));
+    }
+    if archive.messages.len() as u32 > MAX_EXPORT_PAGE {
+        return Err(StdError::generic_err(format!("At most {} messages per page", MAX_EXPORT_PAGE)));
+    }
+
+    let owner = &archive.owner;
+    for message in &archive.messages {
+        if import_message(&mut deps.storage, message, owner)? {
+            config.total_mailboxes += 1;
+        }
+    }
+    let mut messages = vec![];
+    if let Some(settings) = &archive.settings {
+        //this deployment has to be registered with the token before it can take postage in it, like SetMailboxSettings does
+        if let Some(Postage::Snip20 { contract, code_hash, .. }) = &settings.postage {
+            messages.push(register_receive_msg(
+                env.contract_code_hash.clone(),
+                None,
+                BLOCK_SIZE,
+                code_hash.clone(),
+                contract.clone(),
+            )?);
+        }
+        write_mailbox_settings(&mut deps.storage, owner, settings)?;
+    }
+    if !archive.pinned.is_empty() {
+        write_pinned(&mut deps.storage, owner, &archive.pinned)?;
+    }
+    write_config(&mut deps.storage, &config)?;
+
+    Ok(HandleResponse {
+        messages,
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ImportMailbox { imported: archive.messages.len() as u32 })?),
+    })
+}
+
+/*Pinned messages survive unless include_pinned is set, together with their stars and labels. Everything else's meta
+belongs to the generation being dropped*/
 pub fn delete_all_messages<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
-    env: Env
+    env: Env,
+    include_pinned: bool,
 
 ) -> StdResult<HandleResponse> {
-    // Try to access the collection for the account.
-    // If it doesn't exist yet, return an empty collection.
-    let option_error_message = format!("Provided storage doesn't seem like an AppendStore");            
-    let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, env.message.sender.0.as_bytes()], &mut deps.storage);
-    let mut store = AppendStoreMut::<Message, _, _>::attach(&mut store).unwrap_or(Err(StdError::generic_err(option_error_message)))?;
+    let migrated = migrate_on_write(&mut deps.storage, &env.message.sender)?;
+    let mut header = read_mailbox_header(&deps.storage, &env.message.sender)?
+        .ok_or_else(|| StdError::generic_err("You do not have a collection yet"))?;
 
-    store.clear();
+    //pinned messages of the generation being dropped are carried over, so they don't count as deleted
+    let pinned = read_pinned(&deps.storage, &env.message.sender)?;
+    let carried = match (include_pinned, first_stored_id(&deps.storage, &env.message.sender, header.generation)?) {
+        (false, Some(first)) => pinned.iter().filter(|message| message.get_id() >= first).count() as u64,
+        _ => 0,
+    };
+    let mut config = read_config(&deps.storage)?;
+    config.total_mailboxes += migrated as u64;
+    config.messages_deleted += Message::len(&deps.storage, &env.message.sender) as u64 - carried;
+    write_config(&mut deps.storage, &config)?;
 
-    let dummy_message = Message::new(String::from("Placeholder contents"), String::from(env.message.sender.as_str()));
-    let _appending_message = append_message(&mut deps.storage, &dummy_message, &env.message.sender);
+    //moving on to a new generation leaves the old messages unreachable. Compact reclaims them later
+    header.generation += 1;
+    header.sequence += 1;
+    write_mailbox_header(&mut deps.storage, &env.message.sender, &header)?;
+    if !include_pinned {
+        for message in pinned.iter() {
+            let meta = read_message_meta(&deps.storage, &env.message.sender, header.generation - 1, message.get_id())?;
+            write_message_meta(&mut deps.storage, &env.message.sender, header.generation, message.get_id(), &meta)?;
+        }
+    }
+
+    let except = if include_pinned {
+        write_pinned(&mut deps.storage, &env.message.sender, &[])?;
+        vec![]
+    } else {
+        pinned.iter().map(Message::get_id).collect()
+    };
+
+    //lets syncing clients know everything they cached so far is gone, apart from what is still pinned
+    let tombstone = Tombstone { seq: header.sequence, deleted_through: header.sequence - 1, except };
+    append_tombstone(&mut deps.storage, &env.message.sender, &tombstone)?;
     
     Ok(HandleResponse::default())
 
 }
 
+//most messages a single Migrate call will move
+pub const MAX_MIGRATE_STEPS: u32 = 100;
+
+// HandleMsg::Migrate
+/*Admin only. Brings storage written by the original, unversioned layout up to date. State upgrades itself on read, so 
+this only writes it back. Collections are moved over in bounded steps, addresses in the order given, since there is no 
+way to list them from inside the contract. Call it again with the same addresses until done is true*/
+pub fn try_migrate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    addresses: Vec<HumanAddr>,
+    limit: Option<u32>,
+) -> StdResult<HandleResponse> {
+
+    let mut config = only_admin(deps, &env)?;
+    let mut budget = limit.unwrap_or(MAX_MIGRATE_STEPS).min(MAX_MIGRATE_STEPS);
+    let mut moved = 0;
+    let mut done = true;
+
+    for address in &addresses {
+        let legacy = has_legacy_collection(&deps.storage, address);
+        let (steps, finished) = migrate_legacy_collection(&mut deps.storage, address, budget)?;
+        budget -= steps;
+        moved += steps;
+        if legacy && finished {
+            config.total_mailboxes += 1;
+        }
+        if !finished {
+            done = false;
+            break;
+        }
+    }
+
+    write_config(&mut deps.storage, &config)?;
+    write_storage_version(&mut deps.storage, CURRENT_STORAGE_VERSION)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Migrate { moved, done })?),
+    })
+}
+
+//most storage entries a single Compact call will remove
+pub const MAX_COMPACT_STEPS: u32 = 100;
+
+// HandleMsg::Compact
+/*Old generations left behind by delete_all_messages are unreachable but still take up storage. Compact removes their
+entries, oldest generation first, at most `limit` entries per call so the gas of one call stays bounded. Since nobody
+can read old generations anyway, anyone can compact anyone's collection*/
+pub fn try_compact<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    address: Option<HumanAddr>,
+    limit: Option<u32>,
+) -> StdResult<HandleResponse> {
+
+    let address = address.unwrap_or(env.message.sender);
+    let mut header = read_mailbox_header(&deps.storage, &address)?
+        .ok_or_else(|| StdError::generic_err(format!("{} does not have a collection yet", address)))?;
+
+    let home = collection_home(&deps.storage, &address)?;
+    let mut budget = limit.unwrap_or(MAX_COMPACT_STEPS).min(MAX_COMPACT_STEPS);
+    let removed = compact_generations(&mut deps.storage, &home, &mut header, &mut budget)?;
+
+    write_mailbox_header(&mut deps.storage, &address, &header)?;
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::Compact {
+            removed,
+            remaining_generations: header.generation - header.compacted,
+        })?),
+    })
+}
+
+/*Removes the entries of the generations below header.generation that Compact hasn't reached yet, taking one step of
+budget per message. Returns how many messages were removed*/
+fn compact_generations<S: Storage>(
+    storage: &mut S,
+    home: &str,
+    header: &mut MailboxHeader,
+    budget: &mut u32,
+) -> StdResult<u32> {
+    let mut removed = 0;
+
+    while header.compacted < header.generation && *budget > 0 {
+        let generation = header.compacted.to_be_bytes();
+        let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, home.as_bytes(), &generation], storage);
+
+        match remove_last::<StoredMessage, _>(&mut store)?.map(Message::from) {
+            Some(message) => {
+                //messages come off in reverse order, so this message is also the last entry of its sender's index
+                let mut index = PrefixedStorage::multilevel(
+                    &[PREFIX_SENDER_INDEX, home.as_bytes(), &generation, message.get_owner().as_bytes()],
+                    storage
+                );
+                remove_last::<u32, _>(&mut index)?;
+                let mut meta = PrefixedStorage::multilevel(
+                    &[PREFIX_MESSAGE_META, home.as_bytes(), &generation],
+                    storage
+                );
+                meta.remove(&message.get_id().to_be_bytes());
+                let mut legacy_meta = PrefixedStorage::multilevel(
+                    &[PREFIX_LEGACY_MESSAGE_META, home.as_bytes(), &generation],
+                    storage
+                );
+                legacy_meta.remove(&message.get_id().to_be_bytes());
+                let mut chain = PrefixedStorage::multilevel(&[PREFIX_DIGESTS, home.as_bytes(), &generation], storage);
+                remove_last::<[u8; 32], _>(&mut chain)?;
+                *budget -= 1;
+                removed += 1;
+            }
+            None => {
+                PrefixedStorage::multilevel(&[PREFIX_DIGESTS, home.as_bytes(), &generation], storage).remove(DIGEST_HEAD_KEY);
+                header.compacted += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+// HandleMsg::CloseMailbox
+/*Erases the caller's mailbox for good. The first call turns deliveries away and removes everything kept per address:
+settings, labels, pins, forwarding rules, grants, viewing keys and a pending transfer. It also moves the collection on
+to a new generation so every message is left behind for removal. Removing the messages, their index and meta entries
+and the tombstones takes a bounded number of steps per call like Compact, so callers keep calling until done. The 
+header goes last. There is no outbox to clear, messages are only ever stored with their recipient*/
+pub fn try_close_mailbox<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    limit: Option<u32>,
+) -> StdResult<HandleResponse> {
+    let address = env.message.sender;
+    let mut header = read_mailbox_header(&deps.storage, &address)?
+        .ok_or_else(|| StdError::generic_err("You do not have a collection yet, or it is waiting to be migrated"))?;
+
+    if !is_closed(&deps.storage, &address) {
+        set_closed(&mut deps.storage, &address, true);
+
+        let mut config = read_config(&deps.storage)?;
+        config.messages_deleted += Message::len(&deps.storage, &address) as u64;
+        config.total_mailboxes = config.total_mailboxes.saturating_sub(1);
+        write_config(&mut deps.storage, &config)?;
+        header.generation += 1;
+
+        for prefix in &[PREFIX_MAILBOX_SETTINGS, PREFIX_LEGACY_MAILBOX_SETTINGS, PREFIX_LABELS, PREFIX_PINNED, PREFIX_FORWARDING_RULES,
+            PREFIX_MAILBOX_TRANSFERS] {
+            remove_entry(&mut deps.storage, prefix, &address);
+        }
+        for grantee in read_grantees(&deps.storage, PREFIX_READ_GRANTS, &address)? {
+            remove_read_grant(&mut deps.storage, &address, &grantee)?;
+        }
+        for delegate in read_grantees(&deps.storage, PREFIX_SEND_GRANTS, &address)? {
+            remove_send_grant(&mut deps.storage, &address, &delegate)?;
+        }
+        remove_send_window(&mut deps.storage, &[PREFIX_SEND_WINDOW, address.0.as_bytes()]);
+        write_viewing_keys(&mut deps.storage, &deps.api.canonical_address(&address)?, &[])?;
+    }
+
+    let home = collection_home(&deps.storage, &address)?;
+    let mut budget = limit.unwrap_or(MAX_COMPACT_STEPS).min(MAX_COMPACT_STEPS);
+    let mut removed = compact_generations(&mut deps.storage, &home, &mut header, &mut budget)?;
+
+    let mut legacy = PrefixedStorage::multilevel(&[PREFIX_LEGACY_TOMBSTONES, home.as_bytes()], &mut deps.storage);
+    while budget > 0 && remove_last::<LegacyTombstone, _>(&mut legacy)?.is_some() {
+        budget -= 1;
+        removed += 1;
+    }
+    let legacy_left = AppendStore::<LegacyTombstone, _, _>::attach(&legacy).is_some();
+    let mut tombstones = PrefixedStorage::multilevel(&[PREFIX_TOMBSTONES, home.as_bytes()], &mut deps.storage);
+    while budget > 0 && remove_last::<StoredTombstone, _>(&mut tombstones)?.is_some() {
+        budget -= 1;
+        removed += 1;
+    }
+    let tombstones_left = legacy_left || AppendStore::<StoredTombstone, _, _>::attach(&tombstones).is_some();
+
+    let done = header.compacted == header.generation && !tombstones_left;
+    if done {
+        remove_mailbox_header(&mut deps.storage, &address);
+    } else {
+        write_mailbox_header(&mut deps.storage, &address, &header)?;
+    }
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::CloseMailbox { removed, done })?),
+    })
+}
+
 // Bi's notes to self: 
 //
 // Previous version of get_messages returned the vector of messages AND the length of the vector--this overcomplicates things
@@ -219,9 +1572,8 @@ pub fn delete_all_messages<S: Storage, A: Api, Q: Querier>(
 //     store.pop()?;
 // }
 // This is very gas inefficient because a larger collection means more calls to pop().
-// clear() is very gas efficient because it calls set_length(0) on the collection to reset the whole collection with one operation
-// Unfortunately with clear(), the dummy message is cleared aswell. I attempted to call set_length(1) on store but the set_length() fn is private
-// within append_store.rs. I think it wise not to change the visibility of a library fn for security reasons. 
-// Fortunately, we can re-append a dummy message (which contains owner's address) after clearing the list. 
-// Written this way, delete_all_messages performs a constant number of operations no matter the size of the collection.
-// On initial testing, the gas price remains the exact same no matter the size of the list -- will do more testing in future. 
+// The version after that called clear(), which resets the collection with one set_length(0), and then re-appended the dummy 
+// message holding the owner's address because set_length() is private within append_store.rs. clear() never removes the 
+// entries themselves though, so they stayed in storage forever.
+// Now the owner lives in the MailboxHeader and the collection's namespace includes a generation number. Deleting everything is
+// a single header write that moves the collection on to a fresh generation, and Compact removes the old entries in bounded steps. 
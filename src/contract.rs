@@ -1,13 +1,22 @@
-use crate::msg::{HandleMsg, InitMsg, MessageResponse, QueryMsg};
-use crate::state::{Message, State, save, CONFIG_KEY, read_viewing_key, create_empty_collection, append_message};
-use crate::backend::{try_init, get_messages, try_create_viewing_key, delete_all_messages, get_collection_owner, collection_exist};
+use crate::msg::{HandleAnswer, ResponseStatus, MailboxSettingsResponse, HandleMsg, InitMsg, MessageResponse, LabelCount, MessagesSinceResponse, SingleMessageResponse, StarredResponse, ExportMailboxResponse, ViewingKeyInfo, ViewingKeysResponse, PostageResponse, MailboxStatus, MailboxStatusResponse, ContractInfoResponse, QueryMsg, ReceiveMsg};
+use crate::state::{ContractStatusLevel, MAX_VIEWING_KEYS, MailboxSettings, Message, MessageFilter, State, save, load, read_read_grant, LAST_BLOCK_KEY, read_config, write_config,
+    read_storage_version, write_storage_version, CURRENT_STORAGE_VERSION, read_viewing_keys, read_mailbox_settings, is_closed,
+    waits_for_migration, receipt_secret_from};
+use crate::backend::{try_init, get_message, get_messages_since, try_create_viewing_key, delete_all_messages, collection_exist, try_grant_read_access, try_revoke_read_access,
+    try_authorize_sender, try_revoke_sender, use_send_grant,
+    try_set_mailbox_settings, try_revoke_viewing_key, try_set_viewing_key, BLOCK_SIZE, collect_native_postage, collect_snip20_postage, try_set_send_limit, check_rate_limits, try_compact,
+    try_set_contract_status, try_migrate, get_labelled_messages, try_create_label, try_delete_label, try_apply_label, try_set_archived, MAX_COMPACT_STEPS,
+    try_star, try_pin, get_starred_messages, try_set_forwarding_rule, forward_by_rules, accepts_forward,
+    try_propose_mailbox_transfer, try_accept_mailbox_transfer, try_close_mailbox, export_mailbox, archive_digest, try_import_mailbox, MAX_EXPORT_PAGE,
+    get_mailbox_digest};
 use crate::viewing_key::VIEWING_KEY_SIZE;
 
 use cosmwasm_std::{
-    debug_print, to_binary, Api, Binary, Env, Extern, HandleResponse, HumanAddr, InitResponse,
-    Querier, StdError, StdResult, Storage, QueryResult,
+    debug_print, from_binary, log, to_binary, Api, Binary, CanonicalAddr, CosmosMsg, Env, Extern, HandleResponse, HumanAddr, InitResponse,
+    Querier, StdError, StdResult, Storage, QueryResult, Uint128,
 };
 
+use secret_toolkit::utils::{pad_handle_result, pad_query_result};
 use secret_toolkit_crypto::sha_256;
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
@@ -16,15 +25,23 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
     msg: InitMsg,
 ) -> StdResult<InitResponse> {
     
+    let prng_seed = sha_256(base64::encode(msg.prng_seed).as_bytes()).to_vec();
     let config = State {
         owner: deps.api.canonical_address(&env.message.sender)?,
         contract: env.contract.address,
-        prng_seed: sha_256(base64::encode(msg.prng_seed).as_bytes()).to_vec(), 
+        receipt_secret: receipt_secret_from(&prng_seed),
+        prng_seed,
+        send_limit: msg.send_limit,
+        status: ContractStatusLevel::NormalRun,
+        total_mailboxes: 0,
+        messages_sent: 0,
+        messages_deleted: 0,
     };
 
     debug_print!("Contract was initialized by {}", env.message.sender);
 
-    save(&mut deps.storage, CONFIG_KEY, &config)?;
+    write_config(&mut deps.storage, &config)?;
+    write_storage_version(&mut deps.storage, CURRENT_STORAGE_VERSION)?;
     //config(&mut deps.storage).save(&state)?;
 
     Ok(InitResponse::default())
@@ -35,21 +52,67 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
     env: Env,
     msg: HandleMsg,
 ) -> StdResult<HandleResponse> {
-    match msg {
-        HandleMsg::InitAddress { entropy } => try_init(deps, env, entropy),
-        HandleMsg::CreateViewingKey { entropy, .. } => try_create_viewing_key(deps, env, entropy),
-        HandleMsg::SendMessage { to, contents } => send_message(deps, env, to, contents),
-        HandleMsg::DeleteAllMessages {} => delete_all_messages(deps, env)
+    save(&mut deps.storage, LAST_BLOCK_KEY, &env.block.height)?;
+
+    let config = read_config(&deps.storage)?;
+    let stopped = match config.status {
+        ContractStatusLevel::NormalRun => false,
+        ContractStatusLevel::StopSending => matches!(
+            msg,
+            HandleMsg::SendMessage { .. } | HandleMsg::SendMessageAs { .. } | HandleMsg::Receive { .. } | HandleMsg::ForwardMessage { .. }
+        ),
+        ContractStatusLevel::StopAll => !matches!(msg, HandleMsg::SetContractStatus { .. }),
+    };
+    if stopped {
+        return Err(StdError::generic_err("The contract admin has temporarily disabled this action"));
     }
+
+    let response = match msg {
+        HandleMsg::InitAddress { entropy, .. } => try_init(deps, env, entropy),
+        HandleMsg::CreateViewingKey { entropy, label, .. } => try_create_viewing_key(deps, env, entropy, label),
+        HandleMsg::SetViewingKey { key, label, .. } => try_set_viewing_key(deps, env, key, label),
+        HandleMsg::RevokeViewingKey { label, .. } => try_revoke_viewing_key(deps, env, label),
+        HandleMsg::SendMessage { to, contents, .. } => send_message(deps, env, to, contents),
+        HandleMsg::DeleteAllMessages { include_pinned, .. } => delete_all_messages(deps, env, include_pinned.unwrap_or(false)),
+        HandleMsg::SetMailboxSettings { settings, .. } => try_set_mailbox_settings(deps, env, settings),
+        HandleMsg::Receive { from, amount, msg, .. } => try_receive(deps, env, from, amount, msg),
+        HandleMsg::SetSendLimit { limit, .. } => try_set_send_limit(deps, env, limit),
+        HandleMsg::AuthorizeSender { delegate, allowance, .. } => try_authorize_sender(deps, env, delegate, allowance),
+        HandleMsg::RevokeSender { delegate, .. } => try_revoke_sender(deps, env, delegate),
+        HandleMsg::SendMessageAs { from, to, contents, .. } => send_message_as(deps, env, from, to, contents),
+        HandleMsg::GrantReadAccess { grantee, expires_at, filter, .. } => try_grant_read_access(deps, env, grantee, expires_at, filter),
+        HandleMsg::RevokeReadAccess { grantee, .. } => try_revoke_read_access(deps, env, grantee),
+        HandleMsg::CreateLabel { name, .. } => try_create_label(deps, env, name),
+        HandleMsg::DeleteLabel { name, .. } => try_delete_label(deps, env, name),
+        HandleMsg::ApplyLabel { ids, label, remove, .. } => try_apply_label(deps, env, ids, label, remove.unwrap_or(false)),
+        HandleMsg::SetArchived { ids, archived, .. } => try_set_archived(deps, env, ids, archived),
+        HandleMsg::Star { id, starred, .. } => try_star(deps, env, id, starred),
+        HandleMsg::Pin { id, pinned, .. } => try_pin(deps, env, id, pinned),
+        HandleMsg::SetForwardingRule { match_sender, forward_to, .. } => try_set_forwarding_rule(deps, env, match_sender, forward_to),
+        HandleMsg::ForwardMessage { id, to, note, .. } => forward_message(deps, env, id, to, note),
+        HandleMsg::ProposeMailboxTransfer { new_owner, .. } => try_propose_mailbox_transfer(deps, env, new_owner),
+        HandleMsg::AcceptMailboxTransfer { from, .. } => try_accept_mailbox_transfer(deps, env, from),
+        HandleMsg::CloseMailbox { limit, .. } => try_close_mailbox(deps, env, limit),
+        HandleMsg::ImportMailbox { archive, digest, .. } => try_import_mailbox(deps, env, archive, digest),
+        HandleMsg::SetContractStatus { level, .. } => try_set_contract_status(deps, env, level),
+        HandleMsg::Migrate { addresses, limit, .. } => try_migrate(deps, env, addresses, limit),
+        HandleMsg::Compact { address, limit, .. } => try_compact(deps, env, address, limit),
+    };
+    //data is padded so its size doesn't give away e.g. how many messages a user has
+    pad_handle_result(response, BLOCK_SIZE)
 }
 
 pub fn query<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     msg: QueryMsg,
 ) -> StdResult<Binary> {
-    match msg {
-        _=> authenticated_queries(deps, msg), //could just get rid of the "_=>" ? 
-    }
+    let response = match msg {
+        QueryMsg::GetPostage { address, .. } => to_binary(&query_postage(deps, &address)?),
+        QueryMsg::MailboxStatus { address, .. } => to_binary(&query_mailbox_status(deps, &address)?),
+        QueryMsg::ContractInfo { .. } => to_binary(&query_contract_info(deps)?),
+        _ => authenticated_queries(deps, msg),
+    };
+    pad_query_result(response, BLOCK_SIZE)
 } 
 
 fn authenticated_queries<S: Storage, A: Api, Q: Querier>(
@@ -62,16 +125,31 @@ fn authenticated_queries<S: Storage, A: Api, Q: Querier>(
 
         let canonical_addr = deps.api.canonical_address(address)?;
 
-        let expected_key = read_viewing_key(&deps.storage, &canonical_addr);
+        let expected_keys = read_viewing_keys(&deps.storage, &canonical_addr)?;
 
-        if expected_key.is_none() {
+        if expected_keys.is_empty() {
             // Checking the key will take significant time. We don't want to exit immediately if it isn't set
             // in a way which will allow to time the command and determine if a viewing key doesn't exist
             key.check_viewing_key(&[0u8; VIEWING_KEY_SIZE]);
-        } else if key.check_viewing_key(expected_key.unwrap().as_slice()) {
+        // Every key is checked, so the time taken doesn't reveal which of the user's keys matched
+        } else if expected_keys.iter().fold(false, |found, expected| key.check_viewing_key(&expected.hashed) | found) {
 
             return match msg {
-                QueryMsg::GetMessages { behalf, .. } => to_binary(&query_messages(deps, &behalf)?),
+                QueryMsg::GetMessages { behalf, viewer, from_sender, since, until, label, archived, .. } => {
+                    let filter = MessageFilter { from_sender, since, until, label, archived };
+                    to_binary(&query_messages(deps, &behalf, viewer.as_ref(), &filter)?)
+                }
+                QueryMsg::GetMessagesSince { behalf, cursor, .. } => to_binary(&query_messages_since(deps, &behalf, cursor)?),
+                QueryMsg::ListViewingKeys { .. } => to_binary(&query_viewing_keys(deps, &canonical_addr)?),
+                QueryMsg::GetMailboxSettings { behalf, .. } => to_binary(&MailboxSettingsResponse { settings: read_mailbox_settings(&deps.storage, &behalf)? }),
+                QueryMsg::GetMessage { behalf, id, .. } => to_binary(&SingleMessageResponse { message: get_message(&deps.storage, &behalf, id)? }),
+                QueryMsg::GetStarred { behalf, .. } => to_binary(&StarredResponse { messages: get_starred_messages(&deps.storage, &behalf)? }),
+                QueryMsg::GetMailboxDigest { behalf, through, .. } => to_binary(&get_mailbox_digest(&deps.storage, &behalf, through)?),
+                QueryMsg::ExportMailbox { behalf, start, limit, .. } => {
+                    let archive = export_mailbox(&deps.storage, &behalf, start.unwrap_or(0), limit.unwrap_or(MAX_EXPORT_PAGE))?;
+                    let digest = archive_digest(&archive)?;
+                    to_binary(&ExportMailboxResponse { archive, digest })
+                }
                 //QueryMsg::GetWalletInfo { behalf, .. } => to_binary(&query_wallet_info(deps, &behalf)?),
                 _ => panic!("How did this even get to this stage. It should have been processed.")
             };
@@ -88,52 +166,259 @@ pub fn send_message<S: Storage, A: Api, Q: Querier>(
     contents: String,
 ) -> StdResult<HandleResponse> {
 
-    let message = Message::new(String::from(contents), env.message.sender.to_string());
-
-    let already_init = collection_exist(&mut deps.storage, &to);
-    //if "to" does not have a collection yet, the owner of this dummy message will be to because it will be placed
-    //in the collection that this function makes for them 
-    let dummy_message = Message::new(String::from("Dummy_contents.jpg"), String::from(to.as_str()));
-
-    match already_init{
-        false => {
-            //if recipient does not have a list, make one for them. We let them make their own viewing key. - how to notify that they need to make one? 
-            let _storage_space = create_empty_collection(&mut deps.storage, &to);
-            let _dummy_messages = append_message(&mut deps.storage, &dummy_message, &to);
-            let _saved_message = append_message(&mut deps.storage, &message, &to);
-            debug_print(format!("message stored successfully to {}", to));
-            Ok(HandleResponse::default())
-        }
-        true => {
+    let settings = read_mailbox_settings(&deps.storage, &to)?;
+    let postage = collect_native_postage(&env, &env.message.sender, &to, &settings)?;
+    let message = Message::new(contents, env.message.sender.to_string(), env.block.time);
+
+    deliver_message(deps, &env, &env.message.sender, to, message, &settings, postage)
+}
+
+// HandleMsg::ForwardMessage
+/*Delivers a copy of one of the caller's messages as if the caller sent it, so the recipient's allow list, limits and
+postage apply to the caller. The copy keeps the original sender, and records the caller as forwarder and the original's
+delivery time as original_timestamp*/
+pub fn forward_message<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    id: u64,
+    to: HumanAddr,
+    note: Option<String>,
+) -> StdResult<HandleResponse> {
+
+    let original = get_message(&deps.storage, &env.message.sender, id)?;
+    let message = original.forwarded(&env.message.sender, note, env.block.time);
+
+    let settings = read_mailbox_settings(&deps.storage, &to)?;
+    let postage = collect_native_postage(&env, &env.message.sender, &to, &settings)?;
+
+    deliver_message(deps, &env, &env.message.sender, to, message, &settings, postage)
+}
+
+// HandleMsg::SendMessageAs
+/*Like SendMessage, but the message is recorded as coming from from. The caller needs an AuthorizeSender grant from
+them, and pays the postage*/
+pub fn send_message_as<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    to: HumanAddr,
+    contents: String,
+) -> StdResult<HandleResponse> {
+
+    use_send_grant(&mut deps.storage, &from, &env.message.sender)?;
+
+    let settings = read_mailbox_settings(&deps.storage, &to)?;
+    let postage = collect_native_postage(&env, &from, &to, &settings)?;
+    let message = Message::new(contents, from.to_string(), env.block.time);
+
+    deliver_message(deps, &env, &from, to, message, &settings, postage)
+}
 
-            message.store_message(&mut deps.storage, &to)?;
-            debug_print(format!("message stored successfully to {}", to));
-            Ok(HandleResponse::default())
+// HandleMsg::Receive
+/*Called by a SNIP-20 contract after someone sends it tokens with this contract as the recipient. from is whoever sent
+the tokens, so they are the author of the message and the one paying the postage*/
+pub fn try_receive<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    amount: Uint128,
+    msg: Option<Binary>,
+) -> StdResult<HandleResponse> {
+
+    let msg = msg.ok_or_else(|| StdError::generic_err("Receive requires a msg telling us who to deliver to"))?;
+
+    match from_binary(&msg)? {
+        ReceiveMsg::SendMessage { to, contents, .. } => {
+            let settings = read_mailbox_settings(&deps.storage, &to)?;
+            let postage = collect_snip20_postage(&env, &from, &to, amount, &settings)?;
+            let message = Message::new(contents, from.to_string(), env.block.time);
+            deliver_message(deps, &env, &from, to, message, &settings, postage)
         }
+    }
+}
+
+fn deliver_message<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    sender: &HumanAddr,
+    to: HumanAddr,
+    message: Message,
+    settings: &MailboxSettings,
+    postage: Vec<CosmosMsg>,
+) -> StdResult<HandleResponse> {
+
+    if !settings.accepts_from(sender) {
+        return Err(StdError::generic_err(format!("{} only accepts messages from their allow list", to)));
+    }
+    check_rate_limits(&mut deps.storage, env, sender, &to, settings)?;
+
+    //auto_forward is followed once, the target's own auto_forward isn't, so two mailboxes can't bounce a message forever.
+    //the target has to take the message from to like a forwarding rule's would, otherwise it stays with to
+    let mailbox = match settings.auto_forward.clone() {
+        Some(target) if target != to && accepts_forward(&mut deps.storage, env.block.height, &to, &target)? => target,
+        _ => to.clone(),
+    };
+
+    //no branching on whether the mailbox has a collection yet, see append_message. The owner recorded in a new header will be the mailbox
+    let (id, created) = message.store_message(&mut deps.storage, &mailbox)?;
+    debug_print(format!("message stored successfully to {}", mailbox));
+
+    let (copies, copies_created) = forward_by_rules(&mut deps.storage, &to, &mailbox, &message, env)?;
+
+    //saved either way, for the same reason
+    let mut config = read_config(&deps.storage)?;
+    config.messages_sent += 1 + copies;
+    config.total_mailboxes += created as u64 + copies_created;
+    write_config(&mut deps.storage, &config)?;
+
+    //both name the recipient the sender asked for, so they don't give away where their messages are forwarded
+    let log = match settings.notifications.on_delivery {
+        true => vec![log("notify", &to)],
+        false => vec![],
+    };
+    //the id is hashed with a secret so receipts don't reveal how many messages the mailbox has had
+    let data = match settings.receipts {
+        true => {
+            let id = Binary(sha_256(&[config.receipt_secret.as_slice(), mailbox.0.as_bytes(), &id.to_be_bytes()].concat()).to_vec());
+            to_binary(&HandleAnswer::Receipt { to, id, timestamp: env.block.time })?
         }
+        //something is returned either way, so the padded response doesn't tell the sender whether receipts are on
+        false => to_binary(&HandleAnswer::DefaultAnswer { status: ResponseStatus::Success })?,
+    };
+
+    Ok(HandleResponse {
+        messages: postage,
+        log,
+        data: Some(data),
+    })
 }
 
 fn query_messages<S: Storage, A: Api, Q: Querier>(
     deps: &Extern<S, A, Q>,
     behalf: &HumanAddr,
+    viewer: Option<&HumanAddr>,
+    filter: &MessageFilter,
 ) -> StdResult<MessageResponse> {
-    
-    let mut messages: Vec<Message> = Vec::new();
 
-    let owner = get_collection_owner(&deps.storage, &behalf)?;
-    
-    if owner == behalf.to_string() {
-        let msgs = get_messages(
-            &deps.storage,
-            &behalf,
-        )?;
-        messages = msgs
-    } else {
-        return Err(StdError::generic_err("Can only query your own messages!"));
+    //the viewing key was checked against viewer, so reading someone else's messages also takes a live grant from them
+    //queries get no env, so expiry goes by the last handled block and lags until the next transaction
+    let filter = match viewer {
+        Some(viewer) if viewer != behalf => {
+            let grant = read_read_grant(&deps.storage, behalf, viewer)?.ok_or_else(StdError::unauthorized)?;
+            let height: u64 = load(&deps.storage, LAST_BLOCK_KEY).unwrap_or_default();
+            if grant.is_expired(height) {
+                return Err(StdError::unauthorized());
+            }
+            match filter.intersect(&grant.filter) {
+                Some(filter) => filter,
+                None => return Ok(MessageResponse { messages: vec![], labels: vec![], label_counts: vec![] }),
+            }
+        }
+        _ => filter.clone(),
+    };
+
+    let (messages, labels, label_counts) = get_labelled_messages(
+        &deps.storage,
+        behalf,
+        &filter,
+    )?;
+
+    //someone reading through a grant only gets counts for the labels on the messages the grant lets them see
+    let label_counts = match viewer {
+        Some(viewer) if viewer != behalf => label_counts
+            .into_iter()
+            .filter_map(|LabelCount { label, .. }| {
+                let count = labels.iter().filter(|message| message.labels.contains(&label)).count() as u32;
+                if count > 0 {
+                    Some(LabelCount { label, count })
+                } else {
+                    None
+                }
+            })
+            .collect(),
+        _ => label_counts,
+    };
+
+    Ok(MessageResponse { messages, labels, label_counts })
+}
+
+fn query_messages_since<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    behalf: &HumanAddr,
+    cursor: u64,
+) -> StdResult<MessagesSinceResponse> {
+
+    let (messages, tombstones, cursor) = get_messages_since(&deps.storage, behalf, cursor)?;
+
+    Ok(MessagesSinceResponse { messages, tombstones, cursor })
+}
+
+fn query_viewing_keys<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    owner: &CanonicalAddr,
+) -> StdResult<ViewingKeysResponse> {
+
+    let keys = read_viewing_keys(&deps.storage, owner)?
+        .into_iter()
+        .map(|record| ViewingKeyInfo { label: record.label, created_at: record.created_at })
+        .collect();
+
+    Ok(ViewingKeysResponse { keys })
+}
+
+fn query_postage<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+) -> StdResult<PostageResponse> {
+
+    let settings = read_mailbox_settings(&deps.storage, address)?;
+
+    Ok(PostageResponse { postage: settings.postage })
+}
+
+/*initialized means address has a collection, which also happens when someone sends to it first. claimed means its owner 
+has a viewing key, so they can actually read what's in there*/
+fn query_mailbox_status<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+) -> StdResult<MailboxStatusResponse> {
+
+    //closing drops the settings, hide_status included, so a closed mailbox never says anything about itself
+    let settings = read_mailbox_settings(&deps.storage, address)?;
+    if settings.hide_status || is_closed(&deps.storage, address) {
+        return Ok(MailboxStatusResponse { status: None });
     }
 
-    
-    Ok(MessageResponse {messages: messages})
+    let canonical_addr = deps.api.canonical_address(address)?;
+    let status = MailboxStatus {
+        initialized: collection_exist(&deps.storage, address),
+        claimed: !read_viewing_keys(&deps.storage, &canonical_addr)?.is_empty(),
+        //a collection waiting on Migrate turns deliveries away too
+        accepting: settings.accepts_messages() && !waits_for_migration(&deps.storage, address)?,
+    };
+
+    Ok(MailboxStatusResponse { status: Some(status) })
+}
+
+fn query_contract_info<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+) -> StdResult<ContractInfoResponse> {
+
+    let config = read_config(&deps.storage)?;
+
+    Ok(ContractInfoResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        storage_version: read_storage_version(&deps.storage)?,
+        admin: deps.api.human_address(&config.owner)?,
+        status: config.status,
+        send_limit: config.send_limit,
+        max_viewing_keys: MAX_VIEWING_KEYS,
+        max_compact_steps: MAX_COMPACT_STEPS,
+        block_size: BLOCK_SIZE,
+        total_mailboxes: config.total_mailboxes,
+        messages_sent: config.messages_sent,
+        messages_deleted: config.messages_deleted,
+    })
 }
 
 #[cfg(test)]
@@ -141,7 +426,15 @@ mod tests {
     use super::*;
     use cosmwasm_std::testing::{mock_dependencies, mock_env};
     use cosmwasm_std::{coins, from_binary};
-    use crate::msg::{MessageResponse, HandleAnswer/*WalletInfoResponse*/};
+    use crate::state::NotificationPrefs;
+    use crate::msg::{MessageResponse, MessageLabels, LabelCount, ARCHIVE_VERSION, MailboxDigestResponse/*WalletInfoResponse*/};
+    use crate::state::{write_mailbox_transfer, remove_mailbox_transfer, LegacyState, LegacyMessage, LegacyMailboxSettings, MAX_MIGRATE_ON_WRITE, has_legacy_collection, LegacyReadGrant, LegacyTombstone, LegacyMessageMeta, PREFIX_LEGACY_TOMBSTONES, PREFIX_LEGACY_MESSAGE_META, LEGACY_CONFIG_KEY, PREFIX_LEGACY_MAILBOX_SETTINGS, PREFIX_LEGACY_READ_GRANTS, PREFIX_LEGACY_VIEWING_KEY, LegacyMailboxHeader, PREFIX_LEGACY_MAILBOX_HEADER, LegacyViewingKeyRecord,
+        LegacySendGrant, PREFIX_LEGACY_SEND_GRANTS, LegacySendWindow, PREFIX_SEND_WINDOW, LEGACY_SEND_WINDOW_KEY, MessageFilter, Postage, RateLimit, Tombstone, PREFIX_MSGS_RECEIVED, PREFIX_SENDER_INDEX, PREFIX_TOMBSTONES, PREFIX_DIGESTS};
+    use crate::backend::{get_messages, get_collection_owner, get_filtered_messages, export_mailbox, archive_digest, MAX_EXPORT_PAGE};
+    use cosmwasm_std::ReadonlyStorage;
+    use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
+    use secret_toolkit::storage::AppendStoreMut;
+    use cosmwasm_std::BankMsg;
     use crate::viewing_key::ViewingKey;
 
     fn init_for_test<S: Storage, A: Api, Q: Querier> (
@@ -150,13 +443,13 @@ mod tests {
     ) -> ViewingKey {
 
         // Init Contract
-        let msg = InitMsg { prng_seed:String::from("lets init bro")};
+        let msg = InitMsg { prng_seed:String::from("lets init bro"), send_limit: None };
         let env = mock_env("creator", &[]);
         let _res = init(deps, env, msg).unwrap(); 
 
         // Init Address and Create ViewingKey
         let env = mock_env(String::from(&address), &[]);
-        let msg = HandleMsg::InitAddress { entropy: String::from("Entropygoeshereboi") };
+        let msg = HandleMsg::InitAddress { entropy: String::from("Entropygoeshereboi"), padding: None };
         let handle_response = handle(deps, env, msg).unwrap();
 
         match from_binary(&handle_response.data.unwrap()).unwrap() {
@@ -172,7 +465,7 @@ mod tests {
         let mut deps = mock_dependencies(20, &[]);
 
         // init
-        let msg = InitMsg {prng_seed:String::from("lets init bro")};
+        let msg = InitMsg {prng_seed:String::from("lets init bro"), send_limit: None };
         let env = mock_env("anyone", &[]);
         let _res = init(&mut deps, env, msg).unwrap();
         
@@ -180,6 +473,7 @@ mod tests {
         let env = mock_env("anyone", &[]);
         let create_vk_msg = HandleMsg::CreateViewingKey {
             entropy: "supbro".to_string(),
+            label: None,
             padding: None,
         };
         let handle_response = handle(&mut deps, env, create_vk_msg).unwrap();
@@ -207,6 +501,7 @@ mod tests {
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("anyone".to_string()),
             contents: "Hello: sender has shared Pepe.jpg with you".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
@@ -217,20 +512,21 @@ mod tests {
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("anyone".to_string()),
             contents: "Hello: sender has shared Hasbullah.jpg with you".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
         
         // Query Anyone's Messages
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk_anyone.to_string() },).unwrap(); //changing viewing key causes error
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk_anyone.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None },).unwrap(); //changing viewing key causes error
         let value: MessageResponse = from_binary(&query_res).unwrap();
         println!("All messages --> {:#?}", value.messages);        
 
-        let length = Message::len(&mut deps.storage, &HumanAddr::from("anyone"));
+        let length = Message::len(&deps.storage, &HumanAddr::from("anyone"));
         println!("Length of anyone's collection is {}\n", length);
 
         //Query with a different viewing key will fail 
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk_nuggie.to_string() }); //changing viewing key causes error
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk_nuggie.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None }); //changing viewing key causes error
         assert!(query_res.is_err());
 
         //sending a message to nuggie's address
@@ -238,20 +534,21 @@ mod tests {
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("nuggie".to_string()),
             contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
 
         // Query Nuggies's Messages
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("nuggie".to_string()), key: vk_nuggie.to_string() },).unwrap(); //changing viewing key causes error
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("nuggie".to_string()), key: vk_nuggie.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None },).unwrap(); //changing viewing key causes error
         let value: MessageResponse = from_binary(&query_res).unwrap();
         println!("All messages --> {:#?}", value.messages);        
 
-        let length = Message::len(&mut deps.storage, &HumanAddr::from("nuggie"));
+        let length = Message::len(&deps.storage, &HumanAddr::from("nuggie"));
         println!("Length of nuggie's collection is {}\n", length);
 
         //Using anyone's viewing key to query nuggie's messages will fail 
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("nuggie".to_string()), key: vk_anyone.to_string() }); //changing viewing key causes error
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("nuggie".to_string()), key: vk_anyone.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None }); //changing viewing key causes error
         assert!(query_res.is_err());
 
     }
@@ -261,7 +558,7 @@ mod tests {
         let mut deps = mock_dependencies(20, &coins(2, "token"));
 
         // Init Contract
-        let msg = InitMsg { prng_seed:String::from("lets init bro")};
+        let msg = InitMsg { prng_seed:String::from("lets init bro"), send_limit: None };
         let env = mock_env("creator", &[]);
         let _res = init(&mut deps, env, msg).unwrap();
         
@@ -270,17 +567,19 @@ mod tests {
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("anyone".to_string()),
             contents: "Hello: sender has shared Pepe.jpg with you".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
 
-        //SendMessage above will have made a collection for anyone, and placed above message next to dummy message in this collection. 
+        //SendMessage above will have made a collection for anyone, and placed above message in this collection. 
         
         //sending another message to anyone's address
         let env = mock_env("sender", &[]);
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("anyone".to_string()),
             contents: "Hello: sender has shared Hasbullah.jpg with you".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
@@ -291,6 +590,7 @@ mod tests {
         let env = mock_env("anyone", &[]);
         let create_vk_msg = HandleMsg::CreateViewingKey {
             entropy: "supbro".to_string(),
+            label: None,
             padding: None,
         };
         let handle_response = handle(&mut deps, env, create_vk_msg).unwrap();
@@ -304,11 +604,11 @@ mod tests {
         };
 
         // Query Anyone's Messages
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk_anyone.to_string() },).unwrap(); //changing viewing key causes error
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk_anyone.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None },).unwrap(); //changing viewing key causes error
         let value: MessageResponse = from_binary(&query_res).unwrap();
         println!("All messages --> {:#?}", value.messages);        
 
-        let length = Message::len(&mut deps.storage, &HumanAddr::from("anyone"));
+        let length = Message::len(&deps.storage, &HumanAddr::from("anyone"));
         println!("Length of anyone's collection is {}\n", length);
 
     }
@@ -323,44 +623,58 @@ mod tests {
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("anyone".to_string()),
             contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
         
         // Query Messages
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk.to_string() },).unwrap(); //changing viewing key causes error
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None },).unwrap(); //changing viewing key causes error
         let value: MessageResponse = from_binary(&query_res).unwrap();
         println!("All messages --> {:#?}", value.messages);        
 
-        let length = Message::len(&mut deps.storage, &HumanAddr::from("anyone"));
+        let length = Message::len(&deps.storage, &HumanAddr::from("anyone"));
         println!("Length of anyone's collection is {}\n", length);
 
         //delete all messages
         let env = mock_env("anyone", &[]);
-        let msg = HandleMsg::DeleteAllMessages {};
+        let msg = HandleMsg::DeleteAllMessages { include_pinned: None, padding: None };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
 
-        // Query Messages should now only display the dummy message
-        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk.to_string() },).unwrap(); //changing viewing key causes error
+        // Query Messages should now come back empty
+        let query_res = query(&deps, QueryMsg::GetMessages { behalf: HumanAddr("anyone".to_string()), key: vk.to_string(), viewer: None, from_sender: None, since: None, until: None, label: None, archived: None, padding: None },).unwrap(); //changing viewing key causes error
         let value: MessageResponse = from_binary(&query_res).unwrap();
         println!("All messages --> {:#?}", value.messages);        
 
-        let length = Message::len(&mut deps.storage, &HumanAddr::from("anyone"));
+        let length = Message::len(&deps.storage, &HumanAddr::from("anyone"));
         println!("Length of anyone's collection is {}\n", length);
+        assert_eq!(0, length);
+
+        //the collection still belongs to anyone and keeps accepting messages
+        assert_eq!("anyone", get_collection_owner(&deps.storage, &HumanAddr::from("anyone")).unwrap());
+        let env = mock_env("sender", &[]);
+        let msg = HandleMsg::SendMessage {
+            to: HumanAddr("anyone".to_string()),
+            contents: "Sender/hasbullah.jpg".to_string(),
+            padding: None,
+        };
+        handle(&mut deps, env, msg).unwrap();
+        assert_eq!(1, Message::len(&deps.storage, &HumanAddr::from("anyone")));
 
     }
 
     #[test]
     fn get_owner() {
         let mut deps = mock_dependencies(20, &coins(2, "token"));
-        let vk = init_for_test(&mut deps, String::from("anyone"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
         
         //sending a file to anyone's address
         let env = mock_env("sender", &[]);
         let msg = HandleMsg::SendMessage {
             to: HumanAddr("anyone".to_string()),
             contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
         };
         let res = handle(&mut deps, env, msg).unwrap();
         assert_eq!(0, res.messages.len());
@@ -370,6 +684,1631 @@ mod tests {
         println!("{}", owner);
         
     }
+
+    #[test]
+    fn compact_old_generations() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        for i in 0..3 {
+            let env = mock_env("sender", &[]);
+            let msg = HandleMsg::SendMessage {
+                to: HumanAddr("anyone".to_string()),
+                contents: format!("Sender/pepe{}.jpg", i),
+                padding: None,
+            };
+            handle(&mut deps, env, msg).unwrap();
+        }
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+
+        //compaction happens in bounded steps
+        let env = mock_env("keeper", &[]);
+        let msg = HandleMsg::Compact { address: Some(HumanAddr("anyone".to_string())), limit: Some(2), padding: None };
+        let res = handle(&mut deps, env.clone(), msg.clone()).unwrap();
+        match from_binary(&res.data.unwrap()).unwrap() {
+            HandleAnswer::Compact { removed, remaining_generations } => {
+                assert_eq!(2, removed);
+                assert_eq!(1, remaining_generations);
+            }
+            _ => panic!("Unexpected result from handle"),
+        }
+
+        let res = handle(&mut deps, env, msg).unwrap();
+        match from_binary(&res.data.unwrap()).unwrap() {
+            HandleAnswer::Compact { removed, remaining_generations } => {
+                assert_eq!(1, removed);
+                assert_eq!(0, remaining_generations);
+            }
+            _ => panic!("Unexpected result from handle"),
+        }
+
+        //the old entries are gone from storage
+        let old_generation = ReadonlyPrefixedStorage::multilevel(
+            &[PREFIX_MSGS_RECEIVED, b"anyone", &0u32.to_be_bytes()],
+            &deps.storage,
+        );
+        assert_eq!(None, old_generation.get(b"len"));
+        assert_eq!(None, old_generation.get(&0u32.to_be_bytes()));
+        let old_index = ReadonlyPrefixedStorage::multilevel(
+            &[PREFIX_SENDER_INDEX, b"anyone", &0u32.to_be_bytes(), b"sender"],
+            &deps.storage,
+        );
+        assert_eq!(None, old_index.get(b"len"));
+    }
+
+    #[test]
+    fn filter_messages() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        //alice and bob each share something with anyone on three consecutive days
+        for day in 0..3u64 {
+            for sender in &["alice", "bob"] {
+                let mut env = mock_env(*sender, &[]);
+                env.block.time = 1_000_000 + day * 86_400;
+                let msg = HandleMsg::SendMessage {
+                    to: HumanAddr("anyone".to_string()),
+                    contents: format!("{}/day{}.jpg", sender, day),
+                    padding: None,
+                };
+                handle(&mut deps, env, msg).unwrap();
+            }
+        }
+
+        let get = |from_sender: Option<&str>, since: Option<u64>, until: Option<u64>| -> Vec<String> {
+            let msg = QueryMsg::GetMessages {
+                behalf: HumanAddr("anyone".to_string()),
+                key: vk.to_string(),
+                viewer: None,
+                from_sender: from_sender.map(HumanAddr::from),
+                since,
+                until,
+                label: None,
+                archived: None,
+                padding: None,
+            };
+            let value: MessageResponse = from_binary(&query(&deps, msg).unwrap()).unwrap();
+            value.messages.iter().map(|m| m.get_contents().to_string()).collect()
+        };
+
+        assert_eq!(6, get(None, None, None).len());
+        assert_eq!(vec!["alice/day0.jpg", "alice/day1.jpg", "alice/day2.jpg"], get(Some("alice"), None, None));
+        assert_eq!(vec!["alice/day1.jpg", "bob/day1.jpg", "alice/day2.jpg", "bob/day2.jpg"], get(None, Some(1_086_400), None));
+        assert_eq!(vec!["alice/day1.jpg"], get(Some("alice"), Some(1_086_400), Some(1_172_800)));
+        assert_eq!(vec!["bob/day0.jpg", "bob/day1.jpg"], get(Some("bob"), None, Some(1_172_800)));
+        assert!(get(Some("carol"), None, None).is_empty());
+        assert!(get(None, Some(2_000_000), Some(1_000_000)).is_empty());
+    }
+
+    #[test]
+    fn sync_since_cursor() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let send = |deps: &mut Extern<_, _, _>, contents: &str| {
+            let msg = HandleMsg::SendMessage {
+                to: HumanAddr("anyone".to_string()),
+                contents: contents.to_string(),
+                padding: None,
+            };
+            handle(deps, mock_env("sender", &[]), msg).unwrap();
+        };
+        let sync = |deps: &Extern<_, _, _>, cursor: u64| -> MessagesSinceResponse {
+            let msg = QueryMsg::GetMessagesSince { behalf: HumanAddr("anyone".to_string()), key: vk.to_string(), cursor, padding: None };
+            from_binary(&query(deps, msg).unwrap()).unwrap()
+        };
+
+        send(&mut deps, "Sender/pepe.jpg");
+        send(&mut deps, "Sender/hasbullah.jpg");
+        let first = sync(&deps, 0);
+        assert_eq!(2, first.messages.len());
+        assert_eq!(2, first.cursor);
+        assert!(first.tombstones.is_empty());
+
+        //nothing new
+        let second = sync(&deps, first.cursor);
+        assert!(second.messages.is_empty());
+        assert_eq!(2, second.cursor);
+
+        //only the message appended after the cursor comes back
+        send(&mut deps, "Sender/king_pepe.jpg");
+        let third = sync(&deps, second.cursor);
+        assert_eq!(1, third.messages.len());
+        assert_eq!("Sender/king_pepe.jpg", third.messages[0].get_contents());
+        assert_eq!(3, third.messages[0].get_id());
+
+        //deleting everything shows up as a tombstone
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        send(&mut deps, "Sender/queen_pepe.jpg");
+        let fourth = sync(&deps, third.cursor);
+        assert_eq!(vec![Tombstone { seq: 4, deleted_through: 3, except: vec![] }], fourth.tombstones);
+        assert_eq!(1, fourth.messages.len());
+        assert_eq!(5, fourth.messages[0].get_id());
+        assert_eq!(5, fourth.cursor);
+
+        //tombstones from before they were versioned come first
+        let mut legacy_store = PrefixedStorage::multilevel(&[PREFIX_LEGACY_TOMBSTONES, b"anyone"], &mut deps.storage);
+        AppendStoreMut::attach_or_create(&mut legacy_store).unwrap().push(&LegacyTombstone { seq: 2, deleted_through: 1 }).unwrap();
+        assert_eq!(vec![
+            Tombstone { seq: 2, deleted_through: 1, except: vec![] },
+            Tombstone { seq: 4, deleted_through: 3, except: vec![] },
+        ], sync(&deps, 0).tombstones);
+        assert_eq!(1, sync(&deps, 2).tombstones.len());
+    }
+
+    #[test]
+    fn labelled_viewing_keys() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk_default = init_for_test(&mut deps, String::from("anyone"));
+
+        let create = |deps: &mut Extern<_, _, _>, label: &str, entropy: &str| -> ViewingKey {
+            let mut env = mock_env("anyone", &[]);
+            env.block.height = 54321;
+            let msg = HandleMsg::CreateViewingKey { entropy: entropy.to_string(), label: Some(label.to_string()), padding: None };
+            match from_binary(&handle(deps, env, msg).unwrap().data.unwrap()).unwrap() {
+                HandleAnswer::CreateViewingKey { key } => key,
+                _ => panic!("Unexpected result from handle"),
+            }
+        };
+        let vk_phone = create(&mut deps, "phone", "supbro");
+        let vk_laptop = create(&mut deps, "laptop", "heybro");
+
+        let get_messages = |deps: &Extern<_, _, _>, key: &ViewingKey| {
+            query(deps, QueryMsg::GetMessages {
+                behalf: HumanAddr("anyone".to_string()),
+                key: key.to_string(),
+                viewer: None,
+                from_sender: None,
+                since: None,
+                until: None,
+                label: None,
+                archived: None,
+                padding: None,
+            })
+        };
+
+        //every live key works
+        assert!(get_messages(&deps, &vk_default).is_ok());
+        assert!(get_messages(&deps, &vk_phone).is_ok());
+        assert!(get_messages(&deps, &vk_laptop).is_ok());
+
+        let list = QueryMsg::ListViewingKeys { behalf: HumanAddr("anyone".to_string()), key: vk_phone.to_string(), padding: None };
+        let value: ViewingKeysResponse = from_binary(&query(&deps, list).unwrap()).unwrap();
+        let labels: Vec<&str> = value.keys.iter().map(|k| k.label.as_str()).collect();
+        assert_eq!(vec!["default", "phone", "laptop"], labels);
+        assert_eq!(54321, value.keys[1].created_at);
+
+        //revoking the phone's key leaves the others alone
+        let msg = HandleMsg::RevokeViewingKey { label: "phone".to_string(), padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg.clone()).unwrap();
+        assert!(get_messages(&deps, &vk_phone).is_err());
+        assert!(get_messages(&deps, &vk_laptop).is_ok());
+        assert!(get_messages(&deps, &vk_default).is_ok());
+        assert!(handle(&mut deps, mock_env("anyone", &[]), msg).is_err());
+
+        //recreating a label replaces only that key
+        let vk_laptop2 = create(&mut deps, "laptop", "yobro");
+        assert!(get_messages(&deps, &vk_laptop).is_err());
+        assert!(get_messages(&deps, &vk_laptop2).is_ok());
+    }
+
+    #[test]
+    fn prng_seed_rolls_forward() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+        let seed_after_init = read_config(&deps.storage).unwrap().prng_seed;
+
+        //same caller, block and entropy twice in a row still gives two different keys
+        let create = |deps: &mut Extern<_, _, _>| -> ViewingKey {
+            let msg = HandleMsg::CreateViewingKey { entropy: "supbro".to_string(), label: None, padding: None };
+            match from_binary(&handle(deps, mock_env("anyone", &[]), msg).unwrap().data.unwrap()).unwrap() {
+                HandleAnswer::CreateViewingKey { key } => key,
+                _ => panic!("Unexpected result from handle"),
+            }
+        };
+        let first = create(&mut deps);
+        let seed_after_first = read_config(&deps.storage).unwrap().prng_seed;
+        let second = create(&mut deps);
+        let seed_after_second = read_config(&deps.storage).unwrap().prng_seed;
+
+        assert_ne!(first.to_string(), second.to_string());
+        assert_ne!(seed_after_init, seed_after_first);
+        assert_ne!(seed_after_first, seed_after_second);
+    }
+
+    #[test]
+    fn set_viewing_key() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let set = |key: &str| HandleMsg::SetViewingKey { key: key.to_string(), label: Some("keplr".to_string()), padding: None };
+
+        //too short, too repetitive, and too few kinds of characters
+        assert!(handle(&mut deps, mock_env("anyone", &[]), set("Sh0rt!")).is_err());
+        assert!(handle(&mut deps, mock_env("anyone", &[]), set("aaaaaaaaaaAAAAAAAAAA1111111111")).is_err());
+        assert!(handle(&mut deps, mock_env("anyone", &[]), set("abcdefghijklmnopqrstuvwxyz")).is_err());
+
+        let key = "Correct-Horse-Battery-Staple-42";
+        let res = handle(&mut deps, mock_env("anyone", &[]), set(key)).unwrap();
+        match from_binary(&res.data.unwrap()).unwrap() {
+            HandleAnswer::SetViewingKey { status } => assert_eq!(ResponseStatus::Success, status),
+            _ => panic!("Unexpected result from handle"),
+        }
+
+        let msg = QueryMsg::GetMessages {
+            behalf: HumanAddr("anyone".to_string()),
+            key: key.to_string(),
+            viewer: None,
+            from_sender: None,
+            since: None,
+            until: None,
+            label: None,
+            archived: None,
+            padding: None,
+        };
+        assert!(query(&deps, msg).is_ok());
+    }
+
+    #[test]
+    fn responses_are_padded() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let msg = HandleMsg::InitAddress { entropy: String::from("Entropygoeshereboi"), padding: None };
+        let _res = init(&mut deps, mock_env("creator", &[]), InitMsg { prng_seed: String::from("lets init bro"), send_limit: None }).unwrap();
+        let res = handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        let data = res.data.unwrap();
+        assert_eq!(0, data.len() % BLOCK_SIZE);
+        let vk = match from_binary(&data).unwrap() {
+            HandleAnswer::CreateViewingKey { key } => key,
+            _ => panic!("Unexpected result from handle"),
+        };
+
+        let get_messages = |deps: &Extern<_, _, _>| {
+            query(deps, QueryMsg::GetMessages {
+                behalf: HumanAddr("anyone".to_string()),
+                key: vk.to_string(),
+                viewer: None,
+                from_sender: None,
+                since: None,
+                until: None,
+                label: None,
+                archived: None,
+                padding: None,
+            }).unwrap()
+        };
+
+        //an empty inbox and an inbox with a message look the same size from outside
+        let empty = get_messages(&deps);
+        let msg = HandleMsg::SendMessage {
+            to: HumanAddr("anyone".to_string()),
+            contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
+        };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        let one = get_messages(&deps);
+        assert_eq!(0, empty.len() % BLOCK_SIZE);
+        assert_eq!(empty.len(), one.len());
+        let value: MessageResponse = from_binary(&one).unwrap();
+        assert_eq!(1, value.messages.len());
+    }
+
+    #[test]
+    fn delegated_read_access() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk_anyone = init_for_test(&mut deps, String::from("anyone"));
+        let vk_assistant = init_for_test(&mut deps, String::from("assistant"));
+
+        for sender in &["alice", "bob"] {
+            let msg = HandleMsg::SendMessage {
+                to: HumanAddr("anyone".to_string()),
+                contents: format!("{}/pepe.jpg", sender),
+                padding: None,
+            };
+            handle(&mut deps, mock_env(*sender, &[]), msg).unwrap();
+        }
+
+        let read = |deps: &Extern<_, _, _>, key: &ViewingKey| {
+            query(deps, QueryMsg::GetMessages {
+                behalf: HumanAddr("anyone".to_string()),
+                key: key.to_string(),
+                viewer: Some(HumanAddr("assistant".to_string())),
+                from_sender: None,
+                since: None,
+                until: None,
+                label: None,
+                archived: None,
+                padding: None,
+            })
+        };
+
+        //no grant yet
+        assert!(read(&deps, &vk_assistant).is_err());
+
+        //assistant may only see what alice sends, until block 20000
+        let msg = HandleMsg::GrantReadAccess {
+            grantee: HumanAddr("assistant".to_string()),
+            expires_at: Some(20_000),
+            filter: Some(MessageFilter { from_sender: Some(HumanAddr("alice".to_string())), ..Default::default() }),
+            padding: None,
+        };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+
+        let value: MessageResponse = from_binary(&read(&deps, &vk_assistant).unwrap()).unwrap();
+        assert_eq!(1, value.messages.len());
+        assert_eq!("alice", value.messages[0].get_owner());
+
+        //the assistant still has to prove who they are
+        let wrong_key = ViewingKey("anubis_key_wrong".to_string());
+        assert!(read(&deps, &wrong_key).is_err());
+
+        //the grant stops working once the chain passes expires_at
+        let mut env = mock_env("sender", &[]);
+        env.block.height = 20_000;
+        let msg = HandleMsg::SendMessage { to: HumanAddr("someone".to_string()), contents: "x".to_string(), padding: None };
+        handle(&mut deps, env, msg).unwrap();
+        assert!(read(&deps, &vk_assistant).is_err());
+
+        //granting again without expiry, then revoking
+        let msg = HandleMsg::GrantReadAccess {
+            grantee: HumanAddr("assistant".to_string()),
+            expires_at: None,
+            filter: None,
+            padding: None,
+        };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        let value: MessageResponse = from_binary(&read(&deps, &vk_assistant).unwrap()).unwrap();
+        assert_eq!(2, value.messages.len());
+
+        let msg = HandleMsg::RevokeReadAccess { grantee: HumanAddr("assistant".to_string()), padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert!(read(&deps, &vk_assistant).is_err());
+
+        //grants saved before they were versioned still work, and revoking removes them
+        let legacy = LegacyReadGrant { expires_at: None, from_sender: Some(HumanAddr("bob".to_string())), since: None, until: None };
+        let mut legacy_store = PrefixedStorage::multilevel(&[PREFIX_LEGACY_READ_GRANTS, b"anyone"], &mut deps.storage);
+        save(&mut legacy_store, b"assistant", &legacy).unwrap();
+        let value: MessageResponse = from_binary(&read(&deps, &vk_assistant).unwrap()).unwrap();
+        assert_eq!(vec!["bob"], value.messages.iter().map(|message| message.get_owner()).collect::<Vec<_>>());
+
+        let msg = HandleMsg::RevokeReadAccess { grantee: HumanAddr("assistant".to_string()), padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert!(read(&deps, &vk_assistant).is_err());
+    }
+
+    #[test]
+    fn delegated_senders() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let send_as = |deps: &mut Extern<_, _, _>, contents: &str| {
+            let msg = HandleMsg::SendMessageAs {
+                from: HumanAddr("alice".to_string()),
+                to: HumanAddr("anyone".to_string()),
+                contents: contents.to_string(),
+                padding: None,
+            };
+            handle(deps, mock_env("bot", &[]), msg)
+        };
+
+        //bot can't send as alice until she says so
+        assert!(send_as(&mut deps, "shared pepe.jpg").is_err());
+
+        let msg = HandleMsg::AuthorizeSender { delegate: HumanAddr("bot".to_string()), allowance: Some(2), padding: None };
+        handle(&mut deps, mock_env("alice", &[]), msg).unwrap();
+
+        send_as(&mut deps, "shared pepe.jpg").unwrap();
+        send_as(&mut deps, "shared hasbullah.jpg").unwrap();
+        //allowance used up
+        assert!(send_as(&mut deps, "shared doge.jpg").is_err());
+
+        let messages = get_messages(&deps.storage, &HumanAddr("anyone".to_string())).unwrap();
+        assert_eq!(2, messages.len());
+        assert!(messages.iter().all(|message| message.get_owner() == "alice"));
+
+        //an unlimited grant, then revoking it
+        let msg = HandleMsg::AuthorizeSender { delegate: HumanAddr("bot".to_string()), allowance: None, padding: None };
+        handle(&mut deps, mock_env("alice", &[]), msg).unwrap();
+        send_as(&mut deps, "shared doge.jpg").unwrap();
+
+        let msg = HandleMsg::RevokeSender { delegate: HumanAddr("bot".to_string()), padding: None };
+        handle(&mut deps, mock_env("alice", &[]), msg).unwrap();
+        assert!(send_as(&mut deps, "shared wojak.jpg").is_err());
+
+        let query_res = query(&deps, QueryMsg::GetMessages {
+            behalf: HumanAddr("anyone".to_string()),
+            key: vk.to_string(),
+            viewer: None,
+            from_sender: Some(HumanAddr("alice".to_string())),
+            since: None,
+            until: None,
+            label: None,
+            archived: None,
+            padding: None,
+        }).unwrap();
+        let value: MessageResponse = from_binary(&query_res).unwrap();
+        assert_eq!(3, value.messages.len());
+    }
+
+    //wraps a Storage and records every access, so tests can compare what two handles did to storage. Reads are recorded
+    //without what they found, since not finding something is the whole difference we're hiding
+    struct RecordingStorage<S: Storage> {
+        inner: S,
+        accesses: std::cell::RefCell<Vec<(&'static str, usize, usize)>>,
+    }
+
+    impl<S: Storage> ReadonlyStorage for RecordingStorage<S> {
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let value = self.inner.get(key);
+            self.accesses.borrow_mut().push(("get", key.len(), 0));
+            value
+        }
+    }
+
+    impl<S: Storage> Storage for RecordingStorage<S> {
+        fn set(&mut self, key: &[u8], value: &[u8]) {
+            self.accesses.borrow_mut().push(("set", key.len(), value.len()));
+            self.inner.set(key, value)
+        }
+
+        fn remove(&mut self, key: &[u8]) {
+            self.accesses.borrow_mut().push(("remove", key.len(), 0));
+            self.inner.remove(key)
+        }
+    }
+
+    #[test]
+    fn delivery_looks_the_same_for_new_mailboxes() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("oldie1"));
+
+        let mut deps = Extern {
+            storage: RecordingStorage { inner: deps.storage, accesses: Default::default() },
+            api: deps.api,
+            querier: deps.querier,
+        };
+
+        //same length addresses, so only whether the mailbox existed could tell the two apart
+        let mut deliver = |to: &str| {
+            deps.storage.accesses.borrow_mut().clear();
+            let msg = HandleMsg::SendMessage {
+                to: HumanAddr(to.to_string()),
+                contents: "Hello: sender has shared Pepe.jpg with you".to_string(),
+                padding: None,
+            };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+            deps.storage.accesses.replace(vec![])
+        };
+
+        let existing = deliver("oldie1");
+        let new = deliver("newbie");
+        assert_eq!(existing, new);
+    }
+
+    #[test]
+    fn mailbox_status() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let status = |deps: &Extern<_, _, _>, address: &str| {
+            let query_res = query(deps, QueryMsg::MailboxStatus { address: HumanAddr(address.to_string()), padding: None }).unwrap();
+            let value: MailboxStatusResponse = from_binary(&query_res).unwrap();
+            value.status
+        };
+
+        assert_eq!(status(&deps, "anyone"), Some(MailboxStatus { initialized: true, claimed: true, accepting: true }));
+        assert_eq!(status(&deps, "nobody"), Some(MailboxStatus { initialized: false, claimed: false, accepting: true }));
+
+        //receiving a message starts a collection, but nobody has claimed it yet
+        let msg = HandleMsg::SendMessage { to: HumanAddr("newbie".to_string()), contents: "hi".to_string(), padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert_eq!(status(&deps, "newbie"), Some(MailboxStatus { initialized: true, claimed: false, accepting: true }));
+
+        //a closed mailbox lost its hide_status along with its other settings, it stays quiet anyway
+        handle(&mut deps, mock_env("newbie", &[]), HandleMsg::CloseMailbox { limit: None, padding: None }).unwrap();
+        assert_eq!(status(&deps, "newbie"), None);
+
+        //a legacy collection takes deliveries as long as the first one can migrate it, a bigger one waits for Migrate
+        {
+            let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, b"legacy"], &mut deps.storage);
+            let mut store = AppendStoreMut::attach_or_create(&mut store).unwrap();
+            store.push(&LegacyMessage { contents: String::new(), owner: "legacy".to_string() }).unwrap();
+        }
+        assert_eq!(status(&deps, "legacy"), Some(MailboxStatus { initialized: true, claimed: false, accepting: true }));
+        {
+            let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, b"legacy"], &mut deps.storage);
+            let mut store = AppendStoreMut::attach(&mut store).unwrap().unwrap();
+            for _ in 0..MAX_MIGRATE_ON_WRITE {
+                store.push(&LegacyMessage { contents: "pepe.jpg".to_string(), owner: "alice".to_string() }).unwrap();
+            }
+        }
+        assert_eq!(status(&deps, "legacy"), Some(MailboxStatus { initialized: true, claimed: false, accepting: false }));
+
+        //an inbound limit of 0 with nobody on the allow list shuts the mailbox
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings { inbound_limit: Some(RateLimit { max_messages: 0, window: 100 }), ..Default::default() },
+            padding: None,
+        };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert_eq!(status(&deps, "anyone"), Some(MailboxStatus { initialized: true, claimed: true, accepting: false }));
+
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings { hide_status: true, ..Default::default() },
+            padding: None,
+        };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert_eq!(status(&deps, "anyone"), None);
+    }
+
+    #[test]
+    fn get_single_message() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        for contents in &["pepe.jpg", "hasbullah.jpg", "doge.jpg"] {
+            let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: contents.to_string(), padding: None };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        }
+
+        let get = |deps: &Extern<_, _, _>, key: &ViewingKey, id: u64| {
+            query(deps, QueryMsg::GetMessage { behalf: HumanAddr("anyone".to_string()), key: key.to_string(), id, padding: None })
+        };
+
+        let value: SingleMessageResponse = from_binary(&get(&deps, &vk, 2).unwrap()).unwrap();
+        assert_eq!("hasbullah.jpg", value.message.get_contents());
+        assert_eq!(2, value.message.get_id());
+
+        assert!(get(&deps, &vk, 0).is_err());
+        assert!(get(&deps, &vk, 4).is_err());
+        assert!(get(&deps, &ViewingKey("anubis_key_wrong".to_string()), 2).is_err());
+
+        //deleted messages are gone, even though later ids keep counting up
+        let msg = HandleMsg::DeleteAllMessages { include_pinned: None, padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert!(get(&deps, &vk, 2).is_err());
+
+        let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: "wojak.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        let value: SingleMessageResponse = from_binary(&get(&deps, &vk, 5).unwrap()).unwrap();
+        assert_eq!("wojak.jpg", value.message.get_contents());
+    }
+
+    #[test]
+    fn contract_info_and_status() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let send = |deps: &mut Extern<_, _, _>, to: &str| {
+            let msg = HandleMsg::SendMessage { to: HumanAddr(to.to_string()), contents: "pepe.jpg".to_string(), padding: None };
+            handle(deps, mock_env("sender", &[]), msg)
+        };
+        send(&mut deps, "anyone").unwrap();
+        send(&mut deps, "anyone").unwrap();
+        send(&mut deps, "newbie").unwrap();
+        //the pinned message survives, so it isn't counted as deleted
+        let msg = HandleMsg::Pin { id: 1, pinned: true, padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        let msg = HandleMsg::DeleteAllMessages { include_pinned: None, padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+
+        let info = |deps: &Extern<_, _, _>| {
+            let query_res = query(deps, QueryMsg::ContractInfo { padding: None }).unwrap();
+            let value: ContractInfoResponse = from_binary(&query_res).unwrap();
+            value
+        };
+        let value = info(&deps);
+        assert_eq!(HumanAddr("creator".to_string()), value.admin);
+        assert_eq!(ContractStatusLevel::NormalRun, value.status);
+        assert_eq!((2, 3, 1), (value.total_mailboxes, value.messages_sent, value.messages_deleted));
+
+        //only the admin can stop the contract
+        let msg = HandleMsg::SetContractStatus { level: ContractStatusLevel::StopSending, padding: None };
+        assert!(handle(&mut deps, mock_env("anyone", &[]), msg.clone()).is_err());
+        handle(&mut deps, mock_env("creator", &[]), msg).unwrap();
+        assert!(send(&mut deps, "anyone").is_err());
+        let msg = HandleMsg::DeleteAllMessages { include_pinned: None, padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+
+        let msg = HandleMsg::SetContractStatus { level: ContractStatusLevel::StopAll, padding: None };
+        handle(&mut deps, mock_env("creator", &[]), msg).unwrap();
+        let msg = HandleMsg::DeleteAllMessages { include_pinned: None, padding: None };
+        assert!(handle(&mut deps, mock_env("anyone", &[]), msg).is_err());
+        assert_eq!(ContractStatusLevel::StopAll, info(&deps).status);
+
+        let msg = HandleMsg::SetContractStatus { level: ContractStatusLevel::NormalRun, padding: None };
+        handle(&mut deps, mock_env("creator", &[]), msg).unwrap();
+        send(&mut deps, "anyone").unwrap();
+        assert_eq!(4, info(&deps).messages_sent);
+    }
+
+    #[test]
+    fn migrate_legacy_storage() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+
+        //storage as the original, unversioned layout left it
+        let legacy_state = LegacyState {
+            owner: deps.api.canonical_address(&HumanAddr("creator".to_string())).unwrap(),
+            contract: HumanAddr("contract".to_string()),
+            prng_seed: vec![7; 32],
+        };
+        save(&mut deps.storage, LEGACY_CONFIG_KEY, &legacy_state).unwrap();
+        {
+            let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, b"legacy"], &mut deps.storage);
+            let mut store = AppendStoreMut::attach_or_create(&mut store).unwrap();
+            for (contents, owner) in &[("", "legacy"), ("pepe.jpg", "alice"), ("hasbullah.jpg", "bob"), ("doge.jpg", "alice")] {
+                store.push(&LegacyMessage { contents: contents.to_string(), owner: owner.to_string() }).unwrap();
+            }
+        }
+        let vk = ViewingKey("anubis_key_legacy".to_string());
+        let canonical = deps.api.canonical_address(&HumanAddr("legacy".to_string())).unwrap();
+        PrefixedStorage::new(PREFIX_LEGACY_VIEWING_KEY, &mut deps.storage).set(canonical.as_slice(), &vk.to_hashed());
+
+        let read = |deps: &Extern<_, _, _>, from_sender: Option<&str>| {
+            let query_res = query(deps, QueryMsg::GetMessages {
+                behalf: HumanAddr("legacy".to_string()),
+                key: vk.to_string(),
+                viewer: None,
+                from_sender: from_sender.map(HumanAddr::from),
+                since: None,
+                until: None,
+                label: None,
+                archived: None,
+                padding: None,
+            }).unwrap();
+            let value: MessageResponse = from_binary(&query_res).unwrap();
+            value.messages.iter().map(|message| (message.get_id(), message.get_contents().to_string())).collect::<Vec<_>>()
+        };
+        let all = vec![(1, "pepe.jpg".to_string()), (2, "hasbullah.jpg".to_string()), (3, "doge.jpg".to_string())];
+
+        //old records are readable before anything is migrated
+        assert_eq!(0, read_storage_version(&deps.storage).unwrap());
+        assert_eq!(vec![7; 32], read_config(&deps.storage).unwrap().prng_seed);
+        assert_eq!(all, read(&deps, None));
+        assert_eq!(2, read(&deps, Some("alice")).len());
+
+        let legacy_collection = |deps: &mut Extern<_, _, _>, address: &str, len: u32| {
+            let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, address.as_bytes()], &mut deps.storage);
+            let mut store = AppendStoreMut::attach_or_create(&mut store).unwrap();
+            for _ in 0..len {
+                store.push(&LegacyMessage { contents: "pepe.jpg".to_string(), owner: "alice".to_string() }).unwrap();
+            }
+        };
+        let send = |deps: &mut Extern<_, _, _>, to: &str| {
+            let msg = HandleMsg::SendMessage { to: HumanAddr(to.to_string()), contents: "wojak.jpg".to_string(), padding: None };
+            handle(deps, mock_env("sender", &[]), msg)
+        };
+        //a collection too big to move on its first write gets nothing delivered until it has moved
+        legacy_collection(&mut deps, "hoarder", MAX_MIGRATE_ON_WRITE + 1);
+        assert!(send(&mut deps, "hoarder").is_err());
+        let err = handle(&mut deps, mock_env("hoarder", &[]), HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap_err();
+        assert!(err.to_string().contains("waiting to be migrated"));
+
+        let migrate = |deps: &mut Extern<_, _, _>, sender: &str| {
+            let msg = HandleMsg::Migrate { addresses: vec![HumanAddr("legacy".to_string())], limit: Some(2), padding: None };
+            handle(deps, mock_env(sender, &[]), msg)
+        };
+        assert!(migrate(&mut deps, "legacy").is_err());
+
+        let answer: HandleAnswer = from_binary(&migrate(&mut deps, "creator").unwrap().data.unwrap()).unwrap();
+        assert!(matches!(answer, HandleAnswer::Migrate { moved: 2, done: false }));
+        assert_eq!(CURRENT_STORAGE_VERSION, read_storage_version(&deps.storage).unwrap());
+        assert!(deps.storage.get(LEGACY_CONFIG_KEY).is_none());
+        //half moved reads the same
+        assert_eq!(all, read(&deps, None));
+
+        let answer: HandleAnswer = from_binary(&migrate(&mut deps, "creator").unwrap().data.unwrap()).unwrap();
+        assert!(matches!(answer, HandleAnswer::Migrate { moved: 2, done: true }));
+        assert_eq!(all, read(&deps, None));
+        assert_eq!(2, read(&deps, Some("alice")).len());
+
+        //the collection is in the current layout now, ids carry on where the old ones stopped
+        send(&mut deps, "legacy").unwrap();
+        let messages = read(&deps, None);
+        assert_eq!((4, "wojak.jpg".to_string()), messages[3]);
+        assert_eq!(1, read_config(&deps.storage).unwrap().total_mailboxes);
+
+        //smaller collections move by themselves the first time they are written to
+        legacy_collection(&mut deps, "small", 2);
+        send(&mut deps, "small").unwrap();
+        assert!(!has_legacy_collection(&deps.storage, &HumanAddr("small".to_string())));
+        let messages = get_messages(&deps.storage, &HumanAddr("small".to_string())).unwrap();
+        assert_eq!(vec!["pepe.jpg", "wojak.jpg"], messages.iter().map(|message| message.get_contents()).collect::<Vec<_>>());
+        legacy_collection(&mut deps, "tidy", 2);
+        handle(&mut deps, mock_env("tidy", &[]), HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        assert!(get_messages(&deps.storage, &HumanAddr("tidy".to_string())).unwrap().is_empty());
+        assert_eq!(3, read_config(&deps.storage).unwrap().total_mailboxes);
+    }
+
+    #[test]
+    fn mailbox_preferences() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let settings = MailboxSettings {
+            allow_list: vec![HumanAddr("friend".to_string())],
+            accept_from_anyone: false,
+            receipts: true,
+            retention: Some(2),
+            notifications: NotificationPrefs { on_delivery: true, channels: vec!["push".to_string()] },
+            ..Default::default()
+        };
+        let msg = HandleMsg::SetMailboxSettings { settings: settings.clone(), padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+
+        let query_res = query(&deps, QueryMsg::GetMailboxSettings {
+            behalf: HumanAddr("anyone".to_string()), key: vk.to_string(), padding: None,
+        }).unwrap();
+        let value: MailboxSettingsResponse = from_binary(&query_res).unwrap();
+        assert_eq!(settings, value.settings);
+
+        let send = |deps: &mut Extern<_, _, _>, sender: &str, contents: &str| {
+            let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: contents.to_string(), padding: None };
+            handle(deps, mock_env(sender, &[]), msg)
+        };
+
+        //strangers are turned away
+        assert!(send(&mut deps, "stranger", "spam").is_err());
+
+        let mut receipts = std::collections::HashSet::new();
+        let mut receipt_size = 0;
+        let secret = read_config(&deps.storage).unwrap().receipt_secret;
+        for (contents, message_id) in [("pepe.jpg", 1u64), ("hasbullah.jpg", 2), ("doge.jpg", 3)].iter() {
+            //rolling the viewing key seed leaves receipts alone, they come from a secret of their own
+            let msg = HandleMsg::CreateViewingKey { entropy: contents.to_string(), label: Some(contents.to_string()), padding: None };
+            handle(&mut deps, mock_env("friend", &[]), msg).unwrap();
+            let res = send(&mut deps, "friend", contents).unwrap();
+            //logs get padded too
+            assert_eq!(("notify", "anyone"), (res.log[0].key.trim_end(), res.log[0].value.trim_end()));
+            let data = res.data.unwrap();
+            receipt_size = data.len();
+            match from_binary(&data).unwrap() {
+                HandleAnswer::Receipt { to, id, .. } => {
+                    assert_eq!(HumanAddr("anyone".to_string()), to);
+                    //not the plain id
+                    assert_eq!(32, id.len());
+                    assert_eq!(sha_256(&[secret.as_slice(), b"anyone", &message_id.to_be_bytes()].concat()).to_vec(), id.0);
+                    assert!(receipts.insert(id.0));
+                }
+                _ => panic!("expected a receipt"),
+            }
+        }
+
+        //only the newest 2 are kept
+        let messages = get_filtered_messages(&deps.storage, &HumanAddr("anyone".to_string()), &MessageFilter::default()).unwrap();
+        assert_eq!(vec!["hasbullah.jpg", "doge.jpg"], messages.iter().map(|message| message.get_contents()).collect::<Vec<_>>());
+        assert!(get_message(&deps.storage, &HumanAddr("anyone".to_string()), 1).is_err());
+        let (since, tombstones, cursor) = get_messages_since(&deps.storage, &HumanAddr("anyone".to_string()), 0).unwrap();
+        assert_eq!((2, 0), (since.len(), tombstones.len()));
+        //a client that cached the first message is told to drop it
+        let (since, tombstones, _) = get_messages_since(&deps.storage, &HumanAddr("anyone".to_string()), 1).unwrap();
+        assert_eq!(2, since.len());
+        assert_eq!(vec![Tombstone { seq: cursor, deleted_through: 1, except: vec![] }], tombstones);
+
+        //forwarding everything to the assistant
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings { auto_forward: Some(HumanAddr("assistant".to_string())), ..Default::default() },
+            padding: None,
+        };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        let res = send(&mut deps, "stranger", "wojak.jpg").unwrap();
+        //no receipt, but a response the same size as one
+        let data = res.data.unwrap();
+        assert_eq!(receipt_size, data.len());
+        assert!(matches!(from_binary(&data).unwrap(), HandleAnswer::DefaultAnswer { .. }));
+        assert_eq!(1, get_messages(&deps.storage, &HumanAddr("assistant".to_string())).unwrap().len());
+        assert_eq!(3, get_messages(&deps.storage, &HumanAddr("anyone".to_string())).unwrap().len());
+
+        //a target that charges postage isn't forwarded to for free, the message stays with anyone instead
+        let assistant = MailboxSettings {
+            postage: Some(Postage::Native { denom: "uscrt".to_string(), amount: Uint128(100) }),
+            ..Default::default()
+        };
+        handle(&mut deps, mock_env("assistant", &[]), HandleMsg::SetMailboxSettings { settings: assistant.clone(), padding: None }).unwrap();
+        send(&mut deps, "stranger", "pepe2.jpg").unwrap();
+        assert_eq!(1, get_messages(&deps.storage, &HumanAddr("assistant".to_string())).unwrap().len());
+        assert_eq!(4, get_messages(&deps.storage, &HumanAddr("anyone".to_string())).unwrap().len());
+
+        //until the target lets anyone in for free
+        let assistant = MailboxSettings { allow_list: vec![HumanAddr("anyone".to_string())], ..assistant };
+        handle(&mut deps, mock_env("assistant", &[]), HandleMsg::SetMailboxSettings { settings: assistant, padding: None }).unwrap();
+        send(&mut deps, "stranger", "pepe3.jpg").unwrap();
+        assert_eq!(2, get_messages(&deps.storage, &HumanAddr("assistant".to_string())).unwrap().len());
+        assert_eq!(4, get_messages(&deps.storage, &HumanAddr("anyone".to_string())).unwrap().len());
+    }
+
+    #[test]
+    fn labels_and_archive() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        for contents in &["pepe.jpg", "hasbullah.jpg", "doge.jpg"] {
+            let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: contents.to_string(), padding: None };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        }
+
+        let run = |deps: &mut Extern<_, _, _>, msg: HandleMsg| handle(deps, mock_env("anyone", &[]), msg);
+        let read = |deps: &Extern<_, _, _>, label: Option<&str>, archived: Option<bool>| {
+            let query_res = query(deps, QueryMsg::GetMessages {
+                behalf: HumanAddr("anyone".to_string()),
+                key: vk.to_string(),
+                viewer: None,
+                from_sender: None,
+                since: None,
+                until: None,
+                label: label.map(String::from),
+                archived,
+                padding: None,
+            });
+            query_res.map(|res| from_binary::<MessageResponse>(&res).unwrap())
+        };
+
+        //labels have to exist before they can be applied
+        assert!(run(&mut deps, HandleMsg::ApplyLabel { ids: vec![1], label: "memes".to_string(), remove: None, padding: None }).is_err());
+        run(&mut deps, HandleMsg::CreateLabel { name: "memes".to_string(), padding: None }).unwrap();
+        run(&mut deps, HandleMsg::CreateLabel { name: "work".to_string(), padding: None }).unwrap();
+        assert!(run(&mut deps, HandleMsg::CreateLabel { name: "work".to_string(), padding: None }).is_err());
+
+        run(&mut deps, HandleMsg::ApplyLabel { ids: vec![1, 3], label: "memes".to_string(), remove: None, padding: None }).unwrap();
+        run(&mut deps, HandleMsg::ApplyLabel { ids: vec![3], label: "work".to_string(), remove: None, padding: None }).unwrap();
+        run(&mut deps, HandleMsg::SetArchived { ids: vec![1], archived: true, padding: None }).unwrap();
+        //unknown ids are rejected
+        assert!(run(&mut deps, HandleMsg::SetArchived { ids: vec![4], archived: true, padding: None }).is_err());
+
+        let value = read(&deps, None, None).unwrap();
+        assert_eq!(3, value.messages.len());
+        assert_eq!(vec![
+            MessageLabels { id: 1, labels: vec!["memes".to_string()], archived: true, starred: false, pinned: false },
+            MessageLabels { id: 3, labels: vec!["memes".to_string(), "work".to_string()], archived: false, starred: false, pinned: false },
+        ], value.labels);
+        assert_eq!(vec![
+            LabelCount { label: "memes".to_string(), count: 2 },
+            LabelCount { label: "work".to_string(), count: 1 },
+        ], value.label_counts);
+
+        let value = read(&deps, Some("memes"), Some(false)).unwrap();
+        assert_eq!(vec![3], value.messages.iter().map(|message| message.get_id()).collect::<Vec<_>>());
+        assert_eq!(1, value.label_counts[0].count);
+        assert_eq!(2, read(&deps, None, Some(false)).unwrap().messages.len());
+        assert!(read(&deps, Some("nope"), None).is_err());
+
+        //a grant limited to work only counts the labels on what it covers
+        let vk_assistant = init_for_test(&mut deps, String::from("assistant"));
+        let filter = MessageFilter { label: Some("work".to_string()), ..Default::default() };
+        let msg = HandleMsg::GrantReadAccess { grantee: HumanAddr("assistant".to_string()), expires_at: None, filter: Some(filter), padding: None };
+        run(&mut deps, msg).unwrap();
+        let query_res = query(&deps, QueryMsg::GetMessages {
+            behalf: HumanAddr("anyone".to_string()),
+            key: vk_assistant.to_string(),
+            viewer: Some(HumanAddr("assistant".to_string())),
+            from_sender: None,
+            since: None,
+            until: None,
+            label: None,
+            archived: None,
+            padding: None,
+        }).unwrap();
+        let value: MessageResponse = from_binary(&query_res).unwrap();
+        assert_eq!(vec![3], value.messages.iter().map(|message| message.get_id()).collect::<Vec<_>>());
+        assert_eq!(vec![
+            LabelCount { label: "memes".to_string(), count: 1 },
+            LabelCount { label: "work".to_string(), count: 1 },
+        ], value.label_counts);
+
+        //taking a label off, then deleting a label altogether
+        run(&mut deps, HandleMsg::ApplyLabel { ids: vec![1], label: "memes".to_string(), remove: Some(true), padding: None }).unwrap();
+        run(&mut deps, HandleMsg::DeleteLabel { name: "work".to_string(), padding: None }).unwrap();
+        run(&mut deps, HandleMsg::CreateLabel { name: "work".to_string(), padding: None }).unwrap();
+        let value = read(&deps, None, None).unwrap();
+        assert_eq!(vec![
+            MessageLabels { id: 1, labels: vec![], archived: true, starred: false, pinned: false },
+            MessageLabels { id: 3, labels: vec!["memes".to_string()], archived: false, starred: false, pinned: false },
+        ], value.labels);
+        assert_eq!(0, read(&deps, Some("work"), None).unwrap().messages.len());
+
+        //labels go away with the messages
+        run(&mut deps, HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: "wojak.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        assert_eq!(Vec::<MessageLabels>::new(), read(&deps, None, None).unwrap().labels);
+    }
+
+    #[test]
+    fn starred_and_pinned() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+
+        for contents in &["pepe.jpg", "hasbullah.jpg", "doge.jpg"] {
+            let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: contents.to_string(), padding: None };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        }
+
+        let run = |deps: &mut Extern<_, _, _>, msg: HandleMsg| handle(deps, mock_env("anyone", &[]), msg);
+        let read = |deps: &Extern<_, _, _>| {
+            let query_res = query(deps, QueryMsg::GetMessages {
+                behalf: HumanAddr("anyone".to_string()),
+                key: vk.to_string(),
+                viewer: None,
+                from_sender: None,
+                since: None,
+                until: None,
+                label: None,
+                archived: None,
+                padding: None,
+            }).unwrap();
+            from_binary::<MessageResponse>(&query_res).unwrap()
+        };
+        let ids = |messages: &[Message]| messages.iter().map(|message| message.get_id()).collect::<Vec<_>>();
+
+        //message 2 was archived before meta was versioned, starring it keeps that
+        let legacy = LegacyMessageMeta { labels: vec![], archived: true };
+        let mut legacy_store = PrefixedStorage::multilevel(&[PREFIX_LEGACY_MESSAGE_META, b"anyone", &0u32.to_be_bytes()], &mut deps.storage);
+        save(&mut legacy_store, &2u64.to_be_bytes(), &legacy).unwrap();
+        run(&mut deps, HandleMsg::Star { id: 2, starred: true, padding: None }).unwrap();
+        let legacy_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_LEGACY_MESSAGE_META, b"anyone", &0u32.to_be_bytes()], &deps.storage);
+        assert_eq!(None, legacy_store.get(&2u64.to_be_bytes()));
+        run(&mut deps, HandleMsg::Pin { id: 3, pinned: true, padding: None }).unwrap();
+        run(&mut deps, HandleMsg::Pin { id: 1, pinned: true, padding: None }).unwrap();
+        assert!(run(&mut deps, HandleMsg::Pin { id: 4, pinned: true, padding: None }).is_err());
+        assert!(run(&mut deps, HandleMsg::Pin { id: 2, pinned: false, padding: None }).is_err());
+
+        //pinned messages come first, in the order they were pinned
+        let value = read(&deps);
+        assert_eq!(vec![3, 1, 2], ids(&value.messages));
+        assert_eq!(vec![
+            MessageLabels { id: 3, labels: vec![], archived: false, starred: false, pinned: true },
+            MessageLabels { id: 1, labels: vec![], archived: false, starred: false, pinned: true },
+            MessageLabels { id: 2, labels: vec![], archived: true, starred: true, pinned: false },
+        ], value.labels);
+
+        let query_res = query(&deps, QueryMsg::GetStarred { behalf: HumanAddr("anyone".to_string()), key: vk.to_string(), padding: None }).unwrap();
+        let value: StarredResponse = from_binary(&query_res).unwrap();
+        assert_eq!(vec![2], ids(&value.messages));
+        assert!(query(&deps, QueryMsg::GetStarred { behalf: HumanAddr("anyone".to_string()), key: "wrong".to_string(), padding: None }).is_err());
+
+        //pins survive deleting everything else together with their stars, and syncing clients are told which ones did
+        run(&mut deps, HandleMsg::Pin { id: 1, pinned: false, padding: None }).unwrap();
+        run(&mut deps, HandleMsg::Star { id: 3, starred: true, padding: None }).unwrap();
+        run(&mut deps, HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        assert_eq!(vec![3], ids(&read(&deps).messages));
+        let query_res = query(&deps, QueryMsg::GetStarred { behalf: HumanAddr("anyone".to_string()), key: vk.to_string(), padding: None }).unwrap();
+        let value: StarredResponse = from_binary(&query_res).unwrap();
+        assert_eq!(vec![3], ids(&value.messages));
+        assert_eq!("doge.jpg", get_message(&deps.storage, &HumanAddr("anyone".to_string()), 3).unwrap().get_contents());
+        let (_, tombstones, _) = get_messages_since(&deps.storage, &HumanAddr("anyone".to_string()), 0).unwrap();
+        assert_eq!(vec![3], tombstones[0].except);
+
+        run(&mut deps, HandleMsg::DeleteAllMessages { include_pinned: Some(true), padding: None }).unwrap();
+        assert!(read(&deps).messages.is_empty());
+    }
+
+    #[test]
+    fn forwarding_rules() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+        let anyone = HumanAddr("anyone".to_string());
+        let assistant = HumanAddr("assistant".to_string());
+
+        let rule = |deps: &mut Extern<_, _, _>, owner: &str, match_sender: &str, forward_to: Option<&HumanAddr>| {
+            let msg = HandleMsg::SetForwardingRule {
+                match_sender: HumanAddr(match_sender.to_string()),
+                forward_to: forward_to.cloned(),
+                padding: None,
+            };
+            handle(deps, mock_env(owner, &[]), msg)
+        };
+        let send = |deps: &mut Extern<_, _, _>, from: &str, to: &HumanAddr| {
+            let msg = HandleMsg::SendMessage { to: to.clone(), contents: "pepe.jpg".to_string(), padding: None };
+            handle(deps, mock_env(from, &[]), msg).unwrap();
+        };
+
+        assert!(rule(&mut deps, "anyone", "boss", Some(&anyone)).is_err());
+        rule(&mut deps, "anyone", "boss", Some(&assistant)).unwrap();
+        //trying to bounce it straight back
+        rule(&mut deps, "assistant", "boss", Some(&anyone)).unwrap();
+
+        send(&mut deps, "boss", &anyone);
+        send(&mut deps, "sender", &anyone);
+        let mine = get_messages(&deps.storage, &anyone).unwrap();
+        assert_eq!(2, mine.len());
+        assert_eq!(None, mine[0].get_forwarded_by());
+        let theirs = get_messages(&deps.storage, &assistant).unwrap();
+        assert_eq!(1, theirs.len());
+        assert_eq!("boss", theirs[0].get_owner());
+        assert_eq!(Some("anyone"), theirs[0].get_forwarded_by());
+        assert_eq!(mine[0].get_timestamp(), theirs[0].get_timestamp());
+        assert_eq!(3, read_config(&deps.storage).unwrap().messages_sent);
+
+        //targets that don't take messages from the forwarder are skipped without failing the delivery
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings { accept_from_anyone: false, ..Default::default() },
+            padding: None,
+        };
+        handle(&mut deps, mock_env("assistant", &[]), msg).unwrap();
+        send(&mut deps, "boss", &anyone);
+        assert_eq!(1, get_messages(&deps.storage, &assistant).unwrap().len());
+
+        rule(&mut deps, "anyone", "boss", None).unwrap();
+        handle(&mut deps, mock_env("assistant", &[]), HandleMsg::SetMailboxSettings { settings: MailboxSettings::default(), padding: None }).unwrap();
+        send(&mut deps, "boss", &anyone);
+        assert_eq!(1, get_messages(&deps.storage, &assistant).unwrap().len());
+        assert_eq!(4, get_messages(&deps.storage, &anyone).unwrap().len());
+    }
+
+    #[test]
+    fn forward_a_message() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+        let friend = HumanAddr("friend".to_string());
+
+        let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: "pepe.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        let original = get_message(&deps.storage, &HumanAddr("anyone".to_string()), 1).unwrap();
+
+        let send_to_friend = |deps: &mut Extern<_, _, _>, contents: &str, delay: u64| {
+            let mut env = mock_env("sender", &[]);
+            env.block.time += delay;
+            let msg = HandleMsg::SendMessage { to: HumanAddr("friend".to_string()), contents: contents.to_string(), padding: None };
+            handle(deps, env, msg).unwrap();
+        };
+        send_to_friend(&mut deps, "c1.jpg", 300);
+
+        let mut env = mock_env("anyone", &[]);
+        env.block.time += 600;
+        let forward = |id: u64| HandleMsg::ForwardMessage { id, to: friend.clone(), note: Some("lol".to_string()), padding: None };
+        assert!(handle(&mut deps, env.clone(), forward(2)).is_err());
+        handle(&mut deps, env.clone(), forward(1)).unwrap();
+
+        let copy = get_message(&deps.storage, &friend, 2).unwrap();
+        assert_eq!("pepe.jpg", copy.get_contents());
+        assert_eq!("sender", copy.get_owner());
+        assert_eq!(env.block.time, copy.get_timestamp());
+        assert_eq!(Some(original.get_timestamp()), copy.get_original_timestamp());
+        assert_eq!(Some("anyone"), copy.get_forwarded_by());
+        assert_eq!(Some("lol"), copy.get_note());
+
+        //the copy sorts by when it was forwarded, so time filters still find everything around it
+        send_to_friend(&mut deps, "c3.jpg", 900);
+        let filter = MessageFilter { since: Some(original.get_timestamp() + 300), ..Default::default() };
+        let messages = get_filtered_messages(&deps.storage, &friend, &filter).unwrap();
+        assert_eq!(vec!["c1.jpg", "pepe.jpg", "c3.jpg"], messages.iter().map(|message| message.get_contents()).collect::<Vec<_>>());
+
+        //forwarding counts as sending, so the recipient's allow list applies to the forwarder
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings { accept_from_anyone: false, allow_list: vec![HumanAddr("sender".to_string())], ..Default::default() },
+            padding: None,
+        };
+        handle(&mut deps, mock_env("friend", &[]), msg).unwrap();
+        assert!(handle(&mut deps, env, forward(1)).is_err());
+        assert_eq!(3, get_messages(&deps.storage, &friend).unwrap().len());
+    }
+
+    #[test]
+    fn mailbox_transfer() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+        let anyone = HumanAddr("anyone".to_string());
+        let wallet = HumanAddr("newwallet".to_string());
+
+        for contents in &["pepe.jpg", "hasbullah.jpg"] {
+            let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: contents.to_string(), padding: None };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        }
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::CreateLabel { name: "memes".to_string(), padding: None }).unwrap();
+        let msg = HandleMsg::ApplyLabel { ids: vec![2], label: "memes".to_string(), remove: None, padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+
+        let accept = HandleMsg::AcceptMailboxTransfer { from: anyone.clone(), padding: None };
+        assert!(handle(&mut deps, mock_env("newwallet", &[]), accept.clone()).is_err());
+        let msg = HandleMsg::ProposeMailboxTransfer { new_owner: wallet.clone(), padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        assert!(handle(&mut deps, mock_env("stranger", &[]), accept.clone()).is_err());
+        handle(&mut deps, mock_env("newwallet", &[]), accept.clone()).unwrap();
+        //a proposal is good for one transfer
+        assert!(handle(&mut deps, mock_env("newwallet", &[]), accept).is_err());
+
+        //the new wallet reads the whole history, labels included, with the old viewing key
+        assert_eq!("newwallet", get_collection_owner(&deps.storage, &wallet).unwrap());
+        let query_res = query(&deps, QueryMsg::GetMessages {
+            behalf: wallet.clone(),
+            key: vk.to_string(),
+            viewer: None,
+            from_sender: None,
+            since: None,
+            until: None,
+            label: Some("memes".to_string()),
+            archived: None,
+            padding: None,
+        }).unwrap();
+        let value: MessageResponse = from_binary(&query_res).unwrap();
+        assert_eq!(vec!["hasbullah.jpg"], value.messages.iter().map(|message| message.get_contents()).collect::<Vec<_>>());
+        assert_eq!(1, value.label_counts[0].count);
+
+        //the old address starts over empty, without touching what moved
+        assert!(!collection_exist(&deps.storage, &anyone));
+        let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: "doge.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        assert_eq!(1, get_messages(&deps.storage, &anyone).unwrap().len());
+        assert_eq!(2, get_messages(&deps.storage, &wallet).unwrap().len());
+        let msg = HandleMsg::SendMessage { to: wallet.clone(), contents: "wojak.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        //the transfer took sequence number 3
+        assert_eq!("wojak.jpg", get_message(&deps.storage, &wallet, 4).unwrap().get_contents());
+    }
+
+    #[test]
+    fn close_mailbox() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+        let anyone = HumanAddr("anyone".to_string());
+        let friend = HumanAddr("friend".to_string());
+
+        let send = |deps: &mut Extern<_, _, _>| {
+            let msg = HandleMsg::SendMessage { to: HumanAddr("anyone".to_string()), contents: "pepe.jpg".to_string(), padding: None };
+            handle(deps, mock_env("sender", &[]), msg)
+        };
+        let run = |deps: &mut Extern<_, _, _>, msg: HandleMsg| handle(deps, mock_env("anyone", &[]), msg);
+        for _ in 0..3 {
+            send(&mut deps).unwrap();
+        }
+        run(&mut deps, HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        send(&mut deps).unwrap();
+        run(&mut deps, HandleMsg::GrantReadAccess { grantee: friend.clone(), expires_at: None, filter: None, padding: None }).unwrap();
+        let settings = MailboxSettings { receipts: true, ..Default::default() };
+        run(&mut deps, HandleMsg::SetMailboxSettings { settings, padding: None }).unwrap();
+
+        //3 old messages, 1 current one and 1 tombstone to go, 2 at a time
+        let close = |deps: &mut Extern<_, _, _>| {
+            let res = handle(deps, mock_env("anyone", &[]), HandleMsg::CloseMailbox { limit: Some(2), padding: None }).unwrap();
+            match from_binary(&res.data.unwrap()).unwrap() {
+                HandleAnswer::CloseMailbox { removed, done } => (removed, done),
+                _ => panic!("Unexpected result from handle"),
+            }
+        };
+        assert_eq!((2, false), close(&mut deps));
+
+        //closing turns deliveries away and keys stop working straight away
+        assert!(send(&mut deps).is_err());
+        let query_res = query(&deps, QueryMsg::GetMessages {
+            behalf: anyone.clone(),
+            key: vk.to_string(),
+            viewer: None,
+            from_sender: None,
+            since: None,
+            until: None,
+            label: None,
+            archived: None,
+            padding: None,
+        });
+        assert!(query_res.is_err());
+        assert!(run(&mut deps, HandleMsg::InitAddress { entropy: "again".to_string(), padding: None }).is_err());
+
+        //nor can the half erased mailbox be handed over
+        assert!(run(&mut deps, HandleMsg::ProposeMailboxTransfer { new_owner: friend.clone(), padding: None }).is_err());
+        write_mailbox_transfer(&mut deps.storage, &anyone, &friend).unwrap();
+        let msg = HandleMsg::AcceptMailboxTransfer { from: anyone.clone(), padding: None };
+        assert!(handle(&mut deps, mock_env("friend", &[]), msg).is_err());
+        remove_mailbox_transfer(&mut deps.storage, &anyone);
+
+        assert_eq!((2, false), close(&mut deps));
+        assert_eq!((1, true), close(&mut deps));
+        assert!(run(&mut deps, HandleMsg::CloseMailbox { limit: None, padding: None }).is_err());
+
+        assert!(!collection_exist(&deps.storage, &anyone));
+        assert_eq!(None, read_read_grant(&deps.storage, &anyone, &friend).unwrap());
+        assert_eq!(MailboxSettings::default(), read_mailbox_settings(&deps.storage, &anyone).unwrap());
+        assert!(read_viewing_keys(&deps.storage, &deps.api.canonical_address(&anyone).unwrap()).unwrap().is_empty());
+        for namespace in &[
+            vec![PREFIX_MSGS_RECEIVED, b"anyone", &0u32.to_be_bytes()],
+            vec![PREFIX_MSGS_RECEIVED, b"anyone", &1u32.to_be_bytes()],
+            vec![PREFIX_SENDER_INDEX, b"anyone", &1u32.to_be_bytes(), b"sender"],
+            vec![PREFIX_TOMBSTONES, b"anyone"],
+            vec![PREFIX_LEGACY_TOMBSTONES, b"anyone"],
+            vec![PREFIX_DIGESTS, b"anyone", &1u32.to_be_bytes()],
+        ] {
+            assert_eq!(None, ReadonlyPrefixedStorage::multilevel(namespace, &deps.storage).get(b"len"));
+        }
+        assert_eq!(0, read_config(&deps.storage).unwrap().total_mailboxes);
+
+        //until the user comes back
+        run(&mut deps, HandleMsg::InitAddress { entropy: "again".to_string(), padding: None }).unwrap();
+        send(&mut deps).unwrap();
+        assert_eq!(1, get_messages(&deps.storage, &anyone).unwrap().len());
+
+        //or takes over somebody else's mailbox
+        let as_friend = |deps: &mut Extern<_, _, _>, msg: HandleMsg| handle(deps, mock_env("friend", &[]), msg);
+        as_friend(&mut deps, HandleMsg::InitAddress { entropy: "friend".to_string(), padding: None }).unwrap();
+        as_friend(&mut deps, HandleMsg::CloseMailbox { limit: None, padding: None }).unwrap();
+        run(&mut deps, HandleMsg::ProposeMailboxTransfer { new_owner: friend.clone(), padding: None }).unwrap();
+        as_friend(&mut deps, HandleMsg::AcceptMailboxTransfer { from: anyone.clone(), padding: None }).unwrap();
+        let msg = HandleMsg::SendMessage { to: friend.clone(), contents: "doge.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        assert_eq!(2, get_messages(&deps.storage, &friend).unwrap().len());
+    }
+
+    #[test]
+    fn export_and_import() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+        let anyone = HumanAddr("anyone".to_string());
+
+        for contents in &["pepe.jpg", "hasbullah.jpg", "doge.jpg"] {
+            let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: contents.to_string(), padding: None };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        }
+        let settings = MailboxSettings { receipts: true, ..Default::default() };
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::SetMailboxSettings { settings: settings.clone(), padding: None }).unwrap();
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::Pin { id: 2, pinned: true, padding: None }).unwrap();
+
+        let export = |start: Option<u64>| {
+            let query_res = query(&deps, QueryMsg::ExportMailbox { behalf: anyone.clone(), key: vk.to_string(), start, limit: Some(2), padding: None });
+            from_binary::<ExportMailboxResponse>(&query_res.unwrap()).unwrap()
+        };
+        let first = export(None);
+        assert_eq!(ARCHIVE_VERSION, first.archive.version);
+        assert_eq!((2, 3, Some(3)), (first.archive.messages.len(), first.archive.total, first.archive.next));
+        assert_eq!(Some(settings.clone()), first.archive.settings);
+        assert_eq!(vec![2], first.archive.pinned.iter().map(|message| message.get_id()).collect::<Vec<_>>());
+        assert_eq!(Binary(sha_256(&cosmwasm_std::to_vec(&first.archive).unwrap()).to_vec()), first.digest);
+        let second = export(first.archive.next);
+        assert_eq!((1, None, None), (second.archive.messages.len(), second.archive.next, second.archive.settings.clone()));
+
+        //a fresh deployment
+        let mut target = mock_dependencies(20, &coins(2, "token"));
+        init(&mut target, mock_env("creator", &[]), InitMsg { prng_seed: String::from("lets init bro"), send_limit: None }).unwrap();
+        let import = |page: &ExportMailboxResponse| HandleMsg::ImportMailbox {
+            archive: page.archive.clone(),
+            digest: page.digest.clone(),
+            padding: None,
+        };
+        assert!(handle(&mut target, mock_env("anyone", &[]), import(&first)).is_err());
+        let mut tampered = first.clone();
+        tampered.archive.messages.pop();
+        assert!(handle(&mut target, mock_env("creator", &[]), import(&tampered)).is_err());
+
+        handle(&mut target, mock_env("creator", &[]), import(&first)).unwrap();
+        handle(&mut target, mock_env("creator", &[]), import(&second)).unwrap();
+        assert!(handle(&mut target, mock_env("creator", &[]), import(&first)).is_err());
+
+        assert_eq!(get_messages(&deps.storage, &anyone).unwrap(), get_messages(&target.storage, &anyone).unwrap());
+        assert_eq!(settings, read_mailbox_settings(&target.storage, &anyone).unwrap());
+        assert_eq!(1, read_config(&target.storage).unwrap().total_mailboxes);
+        //new deliveries carry on after the imported ids
+        let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: "wojak.jpg".to_string(), padding: None };
+        handle(&mut target, mock_env("sender", &[]), msg).unwrap();
+        assert_eq!("wojak.jpg", get_message(&target.storage, &anyone, 4).unwrap().get_contents());
+
+        //nor can an import go back in time, reads rely on timestamps never going down
+        let other = HumanAddr("other".to_string());
+        let send_to_other = |deps: &mut Extern<_, _, _>, delay: u64| {
+            let mut env = mock_env("sender", &[]);
+            env.block.time += delay;
+            let msg = HandleMsg::SendMessage { to: HumanAddr("other".to_string()), contents: "pepe.jpg".to_string(), padding: None };
+            handle(deps, env, msg).unwrap();
+        };
+        send_to_other(&mut deps, 0);
+        handle(&mut deps, mock_env("other", &[]), HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        send_to_other(&mut deps, 0);
+        send_to_other(&mut target, 600);
+        let archive = export_mailbox(&deps.storage, &other, 0, MAX_EXPORT_PAGE).unwrap();
+        assert_eq!(vec![3], archive.messages.iter().map(|message| message.get_id()).collect::<Vec<_>>());
+        let msg = HandleMsg::ImportMailbox { digest: archive_digest(&archive).unwrap(), archive, padding: None };
+        let err = handle(&mut target, mock_env("creator", &[]), msg).unwrap_err();
+        assert!(err.to_string().contains("older than the last message"));
+
+        //pages go by id, so mail arriving under a retention setting between pages doesn't shift the next one
+        let settings = MailboxSettings { retention: Some(3), ..Default::default() };
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::SetMailboxSettings { settings, padding: None }).unwrap();
+        let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: "wojak.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        let page = export_mailbox(&deps.storage, &anyone, first.archive.next.unwrap(), 2).unwrap();
+        assert_eq!(vec![3, 4], page.messages.iter().map(|message| message.get_id()).collect::<Vec<_>>());
+
+        //a mailbox charging token postage gets the target registered with the token, or it could never be paid
+        let postage = Postage::Snip20 { contract: HumanAddr("sscrt".to_string()), code_hash: "sscrt_hash".to_string(), amount: Uint128(100) };
+        let settings = MailboxSettings { postage: Some(postage), ..Default::default() };
+        handle(&mut deps, mock_env("other", &[]), HandleMsg::SetMailboxSettings { settings, padding: None }).unwrap();
+        let archive = export_mailbox(&deps.storage, &other, 0, MAX_EXPORT_PAGE).unwrap();
+        let mut target = mock_dependencies(20, &coins(2, "token"));
+        init(&mut target, mock_env("creator", &[]), InitMsg { prng_seed: String::from("lets init bro"), send_limit: None }).unwrap();
+        let msg = HandleMsg::ImportMailbox { digest: archive_digest(&archive).unwrap(), archive, padding: None };
+        let res = handle(&mut target, mock_env("creator", &[]), msg).unwrap();
+        assert_eq!(1, res.messages.len());
+    }
+
+    #[test]
+    fn mailbox_digest() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+        let anyone = HumanAddr("anyone".to_string());
+
+        for contents in &["pepe.jpg", "hasbullah.jpg", "doge.jpg"] {
+            let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: contents.to_string(), padding: None };
+            handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        }
+
+        let digest = |deps: &Extern<_, _, _>, through: Option<u64>| {
+            let query_res = query(deps, QueryMsg::GetMailboxDigest { behalf: anyone.clone(), key: vk.to_string(), through, padding: None });
+            from_binary::<MailboxDigestResponse>(&query_res.unwrap()).unwrap()
+        };
+        //what a client does with its cache
+        let fold = |base: &Binary, messages: &[Message]| {
+            let mut digest = base.0.clone();
+            for message in messages {
+                let mut data = digest;
+                data.extend_from_slice(&cosmwasm_std::to_vec(message).unwrap());
+                digest = sha_256(&data).to_vec();
+            }
+            Binary(digest)
+        };
+
+        let cache = get_messages(&deps.storage, &anyone).unwrap();
+        let all = digest(&deps, None);
+        assert_eq!((3, Some(1), Some(3)), (all.count, all.first_id, all.last_id));
+        assert_eq!(Binary(vec![0; 32]), all.base);
+        assert_eq!(all.digest, fold(&all.base, &cache));
+
+        //a client that is behind checks what it has
+        let partial = digest(&deps, Some(2));
+        assert_eq!(2, partial.count);
+        assert_eq!(partial.digest, fold(&partial.base, &cache[..2]));
+        //and a missing entry shows
+        assert_ne!(all.digest, fold(&all.base, &[cache[0].clone(), cache[2].clone()]));
+
+        //retention starts the readable part of the chain further along
+        let msg = HandleMsg::SetMailboxSettings { settings: MailboxSettings { retention: Some(2), ..Default::default() }, padding: None };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+        let retained = digest(&deps, None);
+        assert_eq!((2, Some(2)), (retained.count, retained.first_id));
+        assert_eq!(retained.base, fold(&all.base, &cache[..1]));
+        assert_eq!(all.digest, retained.digest);
+
+        //deleting starts a new chain, and compacting removes the old one
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::DeleteAllMessages { include_pinned: None, padding: None }).unwrap();
+        let emptied = digest(&deps, None);
+        assert_eq!((0, Binary(vec![0; 32])), (emptied.count, emptied.digest));
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::Compact { address: None, limit: None, padding: None }).unwrap();
+        let chain = ReadonlyPrefixedStorage::multilevel(&[PREFIX_DIGESTS, b"anyone", &0u32.to_be_bytes()], &deps.storage);
+        assert_eq!((None, None), (chain.get(b"len"), chain.get(b"head")));
+    }
+
+    #[test]
+    fn native_postage() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        //anyone charges 100uscrt per message, but lets friend in for free
+        let env = mock_env("anyone", &[]);
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings {
+                postage: Some(Postage::Native { denom: "uscrt".to_string(), amount: Uint128(100) }),
+                allow_list: vec![HumanAddr("friend".to_string())],
+                inbound_limit: None,
+                ..Default::default()
+            },
+            padding: None,
+        };
+        handle(&mut deps, env, msg).unwrap();
+
+        let query_res = query(&deps, QueryMsg::GetPostage { address: HumanAddr("anyone".to_string()), padding: None }).unwrap();
+        let value: PostageResponse = from_binary(&query_res).unwrap();
+        assert_eq!(value.postage, Some(Postage::Native { denom: "uscrt".to_string(), amount: Uint128(100) }));
+
+        //not paying enough fails
+        let msg = HandleMsg::SendMessage {
+            to: HumanAddr("anyone".to_string()),
+            contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
+        };
+        let env = mock_env("stranger", &coins(99, "uscrt"));
+        assert!(handle(&mut deps, env, msg.clone()).is_err());
+        let env = mock_env("stranger", &coins(100, "token"));
+        assert!(handle(&mut deps, env, msg.clone()).is_err());
+
+        //paying the postage forwards the funds to the recipient
+        let env = mock_env("stranger", &coins(100, "uscrt"));
+        let res = handle(&mut deps, env, msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr("cosmos2contract".to_string()),
+            to_address: HumanAddr("anyone".to_string()),
+            amount: coins(100, "uscrt"),
+        })]);
+
+        //senders on the allow list skip the fee
+        let env = mock_env("friend", &[]);
+        let res = handle(&mut deps, env, msg).unwrap();
+        assert_eq!(0, res.messages.len());
+
+        assert_eq!(2, Message::len(&deps.storage, &HumanAddr::from("anyone")));
+    }
+
+    #[test]
+    fn snip20_postage() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        let postage = Postage::Snip20 {
+            contract: HumanAddr("sscrt".to_string()),
+            code_hash: "sscrt_hash".to_string(),
+            amount: Uint128(100),
+        };
+        let env = mock_env("anyone", &[]);
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings { postage: Some(postage), ..Default::default() },
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg).unwrap();
+        //registers with the token so it calls us back on Send
+        assert_eq!(1, res.messages.len());
+
+        //native SendMessage can't pay token postage
+        let env = mock_env("stranger", &[]);
+        let msg = HandleMsg::SendMessage {
+            to: HumanAddr("anyone".to_string()),
+            contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
+        };
+        assert!(handle(&mut deps, env, msg).is_err());
+
+        let receive_msg = to_binary(&ReceiveMsg::SendMessage {
+            to: HumanAddr("anyone".to_string()),
+            contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
+        }).unwrap();
+
+        //tokens from a contract the recipient didn't ask for are refused
+        let env = mock_env("other_token", &[]);
+        let msg = HandleMsg::Receive {
+            sender: HumanAddr("stranger".to_string()),
+            from: HumanAddr("stranger".to_string()),
+            amount: Uint128(100),
+            msg: Some(receive_msg.clone()),
+            padding: None,
+        };
+        assert!(handle(&mut deps, env, msg).is_err());
+
+        //not enough tokens
+        let env = mock_env("sscrt", &[]);
+        let msg = HandleMsg::Receive {
+            sender: HumanAddr("stranger".to_string()),
+            from: HumanAddr("stranger".to_string()),
+            amount: Uint128(50),
+            msg: Some(receive_msg.clone()),
+            padding: None,
+        };
+        assert!(handle(&mut deps, env, msg).is_err());
+
+        let env = mock_env("sscrt", &[]);
+        let msg = HandleMsg::Receive {
+            sender: HumanAddr("stranger".to_string()),
+            from: HumanAddr("stranger".to_string()),
+            amount: Uint128(100),
+            msg: Some(receive_msg),
+            padding: None,
+        };
+        let res = handle(&mut deps, env, msg).unwrap();
+        //tokens are transferred on to the recipient
+        assert_eq!(1, res.messages.len());
+
+        let messages = get_messages(&deps.storage, &HumanAddr::from("anyone")).unwrap();
+        assert_eq!("stranger", messages[0].get_owner());
+    }
+
+    #[test]
+    fn unversioned_mailbox_settings() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let vk = init_for_test(&mut deps, String::from("anyone"));
+        let anyone = HumanAddr("anyone".to_string());
+
+        //settings saved before they were versioned
+        let legacy = LegacyMailboxSettings {
+            postage: Some(Postage::Native { denom: "uscrt".to_string(), amount: Uint128(100) }),
+            allow_list: vec![HumanAddr("friend".to_string())],
+            inbound_limit: None,
+            hide_status: true,
+        };
+        let mut legacy_store = PrefixedStorage::new(PREFIX_LEGACY_MAILBOX_SETTINGS, &mut deps.storage);
+        save(&mut legacy_store, anyone.0.as_bytes(), &legacy).unwrap();
+
+        //still charge postage, and the new preferences get their defaults
+        let msg = HandleMsg::SendMessage { to: anyone.clone(), contents: "pepe.jpg".to_string(), padding: None };
+        assert!(handle(&mut deps, mock_env("stranger", &[]), msg.clone()).is_err());
+        handle(&mut deps, mock_env("stranger", &coins(100, "uscrt")), msg.clone()).unwrap();
+        let settings = read_mailbox_settings(&deps.storage, &anyone).unwrap();
+        assert!(settings.hide_status && settings.accept_from_anyone);
+        assert_eq!(None, settings.auto_forward);
+
+        //saving them again moves them to the current layout
+        let settings = MailboxSettings { receipts: true, ..settings };
+        handle(&mut deps, mock_env("anyone", &[]), HandleMsg::SetMailboxSettings { settings: settings.clone(), padding: None }).unwrap();
+        assert_eq!(None, ReadonlyPrefixedStorage::new(PREFIX_LEGACY_MAILBOX_SETTINGS, &deps.storage).get(anyone.0.as_bytes()));
+        let query_res = query(&deps, QueryMsg::GetMailboxSettings { behalf: anyone.clone(), key: vk.to_string(), padding: None }).unwrap();
+        assert_eq!(settings, from_binary::<MailboxSettingsResponse>(&query_res).unwrap().settings);
+    }
+
+    #[test]
+    fn unversioned_records() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+        let oldie = HumanAddr("oldie".to_string());
+
+        //a header saved before headers were versioned, read until the next delivery writes it again
+        let header = LegacyMailboxHeader { owner: "oldie".to_string(), generation: 0, compacted: 0, sequence: 5 };
+        save(&mut PrefixedStorage::new(PREFIX_LEGACY_MAILBOX_HEADER, &mut deps.storage), b"oldie", &header).unwrap();
+        assert!(collection_exist(&deps.storage, &oldie));
+        let msg = HandleMsg::SendMessage { to: oldie.clone(), contents: "pepe.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("sender", &[]), msg).unwrap();
+        assert_eq!(6, get_messages(&deps.storage, &oldie).unwrap()[0].get_id());
+        assert_eq!(None, ReadonlyPrefixedStorage::new(PREFIX_LEGACY_MAILBOX_HEADER, &deps.storage).get(b"oldie"));
+
+        //an unversioned list of viewing keys keeps working, and moves over once the keys change
+        let vk = ViewingKey("anubis_key_oldie".to_string());
+        let canonical = deps.api.canonical_address(&oldie).unwrap();
+        let keys = vec![LegacyViewingKeyRecord { label: "phone".to_string(), hashed: vk.to_hashed().to_vec(), created_at: 1 }];
+        save(&mut PrefixedStorage::new(PREFIX_LEGACY_VIEWING_KEY, &mut deps.storage), canonical.as_slice(), &keys).unwrap();
+        let settings = |deps: &Extern<_, _, _>| query(deps, QueryMsg::GetMailboxSettings { behalf: oldie.clone(), key: vk.to_string(), padding: None });
+        settings(&deps).unwrap();
+        let msg = HandleMsg::CreateViewingKey { entropy: "laptop".to_string(), label: Some("laptop".to_string()), padding: None };
+        handle(&mut deps, mock_env("oldie", &[]), msg).unwrap();
+        assert_eq!(None, ReadonlyPrefixedStorage::new(PREFIX_LEGACY_VIEWING_KEY, &deps.storage).get(canonical.as_slice()));
+        settings(&deps).unwrap();
+
+        //an unversioned send grant still counts down its allowance
+        let grant = LegacySendGrant { allowance: Some(1) };
+        save(&mut PrefixedStorage::multilevel(&[PREFIX_LEGACY_SEND_GRANTS, b"oldie"], &mut deps.storage), b"assistant", &grant).unwrap();
+        let send_as = HandleMsg::SendMessageAs { from: oldie.clone(), to: HumanAddr("anyone".to_string()), contents: "pepe.jpg".to_string(), padding: None };
+        handle(&mut deps, mock_env("assistant", &[]), send_as.clone()).unwrap();
+        assert!(handle(&mut deps, mock_env("assistant", &[]), send_as).is_err());
+
+        //as does an unversioned rate limit window
+        let limit = Some(RateLimit { max_messages: 1, window: 10 });
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetSendLimit { limit, padding: None }).unwrap();
+        let window = LegacySendWindow { start: mock_env("sender", &[]).block.height, count: 1 };
+        save(&mut PrefixedStorage::multilevel(&[PREFIX_SEND_WINDOW, b"sender"], &mut deps.storage), LEGACY_SEND_WINDOW_KEY, &window).unwrap();
+        let msg = HandleMsg::SendMessage { to: oldie.clone(), contents: "pepe.jpg".to_string(), padding: None };
+        assert!(handle(&mut deps, mock_env("sender", &[]), msg).is_err());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let mut deps = mock_dependencies(20, &coins(2, "token"));
+        let _vk = init_for_test(&mut deps, String::from("anyone"));
+
+        //only the admin can set the contract-wide limit
+        let limit = Some(RateLimit { max_messages: 2, window: 10 });
+        let env = mock_env("anyone", &[]);
+        assert!(handle(&mut deps, env, HandleMsg::SetSendLimit { limit: limit.clone(), padding: None }).is_err());
+        let env = mock_env("creator", &[]);
+        handle(&mut deps, env, HandleMsg::SetSendLimit { limit, padding: None }).unwrap();
+
+        let send = |to: &str| HandleMsg::SendMessage {
+            to: HumanAddr(to.to_string()),
+            contents: "Sender/pepe.jpg".to_string(),
+            padding: None,
+        };
+
+        let mut env = mock_env("spammer", &[]);
+        handle(&mut deps, env.clone(), send("anyone")).unwrap();
+        handle(&mut deps, env.clone(), send("nuggie")).unwrap();
+        assert!(handle(&mut deps, env.clone(), send("someone")).is_err());
+
+        //once the window has passed the sender can send again
+        env.block.height += 10;
+        handle(&mut deps, env.clone(), send("someone")).unwrap();
+
+        //anyone only accepts one message per window from each sender
+        let msg = HandleMsg::SetMailboxSettings {
+            settings: MailboxSettings {
+                inbound_limit: Some(RateLimit { max_messages: 1, window: 100 }),
+                ..Default::default()
+            },
+            padding: None,
+        };
+        handle(&mut deps, mock_env("anyone", &[]), msg).unwrap();
+
+        let env = mock_env("sender", &[]);
+        handle(&mut deps, env.clone(), send("anyone")).unwrap();
+        assert!(handle(&mut deps, env, send("anyone")).is_err());
+        //other senders are counted separately
+        handle(&mut deps, mock_env("other_sender", &[]), send("anyone")).unwrap();
+    }
 }   
    
  /*Bi's notes to self: 
@@ -1,33 +1,122 @@
-use cosmwasm_std::HumanAddr;
+use cosmwasm_std::{Binary, HumanAddr, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{state::Message, viewing_key::ViewingKey};
+use crate::{state::{ContractStatusLevel, MailboxSettings, Message, MessageFilter, Postage, RateLimit, Tombstone}, viewing_key::ViewingKey};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InitMsg {
     pub prng_seed: String,
+    pub send_limit: Option<RateLimit>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum HandleMsg {
-    InitAddress {entropy: String},
-    CreateViewingKey { entropy: String, padding: Option<String>},
-    SendMessage { to: HumanAddr, contents: String },
-    DeleteAllMessages {}
+    InitAddress {entropy: String, padding: Option<String>},
+    //label defaults to "default". Creating a key under a label you already use replaces that key only
+    CreateViewingKey { entropy: String, label: Option<String>, padding: Option<String>},
+    //client-chosen key, for wallets that follow the SNIP-20 flow
+    SetViewingKey { key: String, label: Option<String>, padding: Option<String> },
+    RevokeViewingKey { label: String, padding: Option<String> },
+    SendMessage { to: HumanAddr, contents: String, padding: Option<String> },
+    //pinned messages are kept unless include_pinned is true
+    DeleteAllMessages { include_pinned: Option<bool>, padding: Option<String> },
+    SetMailboxSettings { settings: MailboxSettings, padding: Option<String> },
+    //SNIP-20 callback, used to pay postage in tokens. msg is a ReceiveMsg
+    Receive { sender: HumanAddr, from: HumanAddr, amount: Uint128, msg: Option<Binary>, padding: Option<String> },
+    //admin only
+    SetSendLimit { limit: Option<RateLimit>, padding: Option<String> },
+    //lets delegate send messages as you, at most allowance of them if set
+    AuthorizeSender { delegate: HumanAddr, allowance: Option<u32>, padding: Option<String> },
+    RevokeSender { delegate: HumanAddr, padding: Option<String> },
+    //sends a message recorded as coming from from, who must have authorized you
+    SendMessageAs { from: HumanAddr, to: HumanAddr, contents: String, padding: Option<String> },
+    //lets grantee read your messages matching filter until block height expires_at
+    GrantReadAccess { grantee: HumanAddr, expires_at: Option<u64>, filter: Option<MessageFilter>, padding: Option<String> },
+    RevokeReadAccess { grantee: HumanAddr, padding: Option<String> },
+    CreateLabel { name: String, padding: Option<String> },
+    DeleteLabel { name: String, padding: Option<String> },
+    //labels the messages with these ids, or takes the label off them if remove is true
+    ApplyLabel { ids: Vec<u64>, label: String, remove: Option<bool>, padding: Option<String> },
+    SetArchived { ids: Vec<u64>, archived: bool, padding: Option<String> },
+    Star { id: u64, starred: bool, padding: Option<String> },
+    //pinned messages come first in GetMessages and survive DeleteAllMessages
+    Pin { id: u64, pinned: bool, padding: Option<String> },
+    //copies messages from match_sender to forward_to after they are delivered to you. no forward_to removes the rule
+    SetForwardingRule { match_sender: HumanAddr, forward_to: Option<HumanAddr>, padding: Option<String> },
+    //sends a copy of one of your messages to to, keeping its sender and timestamp
+    ForwardMessage { id: u64, to: HumanAddr, note: Option<String>, padding: Option<String> },
+    //hands your mailbox over to new_owner once they accept it with AcceptMailboxTransfer
+    ProposeMailboxTransfer { new_owner: HumanAddr, padding: Option<String> },
+    AcceptMailboxTransfer { from: HumanAddr, padding: Option<String> },
+    //erases your mailbox. keep calling it until it reports done
+    CloseMailbox { limit: Option<u32>, padding: Option<String> },
+    //admin only. appends a page of ExportMailbox to the archive owner's collection
+    ImportMailbox { archive: MailboxArchive, digest: Binary, padding: Option<String> },
+    //admin only
+    SetContractStatus { level: ContractStatusLevel, padding: Option<String> },
+    //admin only. moves collections of the original layout into the current one
+    Migrate { addresses: Vec<HumanAddr>, limit: Option<u32>, padding: Option<String> },
+    //reclaims storage left behind by DeleteAllMessages. address defaults to the caller
+    Compact { address: Option<HumanAddr>, limit: Option<u32>, padding: Option<String> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveMsg {
+    SendMessage { to: HumanAddr, contents: String, padding: Option<String> },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    GetMessages { behalf: HumanAddr, key: String } 
+    GetMessages {
+        behalf: HumanAddr,
+        //viewer's key if viewer is set, otherwise behalf's key
+        key: String,
+        //set to read behalf's messages through a read grant behalf gave to viewer
+        viewer: Option<HumanAddr>,
+        //only messages from this sender
+        from_sender: Option<HumanAddr>,
+        //only messages delivered at or after this block time
+        since: Option<u64>,
+        //only messages delivered before this block time
+        until: Option<u64>,
+        //only messages with this label
+        label: Option<String>,
+        //only archived messages if true, only the rest if false
+        archived: Option<bool>,
+        padding: Option<String>,
+    },
+    GetPostage { address: HumanAddr, padding: Option<String> },
+    //whether address has a collection, has set up a viewing key and is taking messages
+    MailboxStatus { address: HumanAddr, padding: Option<String> },
+    ContractInfo { padding: Option<String> },
+    //everything appended or deleted after sequence number cursor. Start with a cursor of 0
+    GetMessagesSince { behalf: HumanAddr, key: String, cursor: u64, padding: Option<String> },
+    ListViewingKeys { behalf: HumanAddr, key: String, padding: Option<String> },
+    //a single message, by the id it was given when it was delivered
+    GetMessage { behalf: HumanAddr, key: String, id: u64, padding: Option<String> },
+    GetMailboxSettings { behalf: HumanAddr, key: String, padding: Option<String> },
+    GetStarred { behalf: HumanAddr, key: String, padding: Option<String> },
+    //one page of your mailbox as a portable archive. start with 0, then pass next
+    ExportMailbox { behalf: HumanAddr, key: String, start: Option<u64>, limit: Option<u32>, padding: Option<String> },
+    //hash chain over your messages, up to the one with id through if set
+    GetMailboxDigest { behalf: HumanAddr, key: String, through: Option<u64>, padding: Option<String> },
 }
 
 impl QueryMsg {
     pub fn get_validation_params(&self) -> (Vec<&HumanAddr>, ViewingKey) {
         match self {
-            Self::GetMessages { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::GetMessages { behalf, key, viewer, .. } => (vec![viewer.as_ref().unwrap_or(behalf)], ViewingKey(key.clone())),
+            Self::GetMessagesSince { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::ListViewingKeys { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::GetMessage { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::GetMailboxSettings { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::GetStarred { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::ExportMailbox { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
+            Self::GetMailboxDigest { behalf, key, .. } => (vec![behalf], ViewingKey(key.clone())),
             _ => panic!("This query type does not require authentication"),
         }
     }
@@ -38,6 +127,13 @@ impl QueryMsg {
 pub enum HandleAnswer {
     DefaultAnswer { status:ResponseStatus},
     CreateViewingKey { key: ViewingKey },
+    SetViewingKey { status: ResponseStatus },
+    Compact { removed: u32, remaining_generations: u32 },
+    Migrate { moved: u32, done: bool },
+    CloseMailbox { removed: u32, done: bool },
+    ImportMailbox { imported: u32 },
+    //returned by deliveries to mailboxes that turned receipts on. id is opaque, the real id would give away the recipient's sequence number
+    Receipt { to: HumanAddr, id: Binary, timestamp: u64 },
 }
 
 // We define a custom struct for each query response
@@ -45,8 +141,132 @@ pub enum HandleAnswer {
 
 pub struct MessageResponse {
     pub messages: Vec<Message>,
+    //labels and flags of those of the messages that have any
+    pub labels: Vec<MessageLabels>,
+    //how many messages matching the filter, apart from its label, carry each of your labels
+    pub label_counts: Vec<LabelCount>,
 } 
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MessageLabels {
+    pub id: u64,
+    pub labels: Vec<String>,
+    pub archived: bool,
+    pub starred: bool,
+    pub pinned: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LabelCount {
+    pub label: String,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SingleMessageResponse {
+    pub message: Message,
+}
+
+//bumped whenever MailboxArchive changes, so ImportMailbox can tell what it was given
+pub const ARCHIVE_VERSION: u32 = 1;
+
+// One page of an exported mailbox. Settings and pinned messages come with the first page only
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MailboxArchive {
+    pub version: u32,
+    pub owner: HumanAddr,
+    pub settings: Option<MailboxSettings>,
+    pub pinned: Vec<Message>,
+    pub messages: Vec<Message>,
+    //id this page was asked to start at, 0 for the first page
+    pub start: u64,
+    pub total: u32,
+    //id the next page starts at, if there is one
+    pub next: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ExportMailboxResponse {
+    pub archive: MailboxArchive,
+    //SHA-256 of the archive serialized as JSON. A checksum, not a signature
+    pub digest: Binary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MailboxDigestResponse {
+    //where the chain stands after last_id
+    pub digest: Binary,
+    //where the chain stood before first_id
+    pub base: Binary,
+    //messages between base and digest, first_id through last_id
+    pub count: u32,
+    pub first_id: Option<u64>,
+    pub last_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StarredResponse {
+    pub messages: Vec<Message>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MessagesSinceResponse {
+    pub messages: Vec<Message>,
+    pub tombstones: Vec<Tombstone>,
+    //pass this as the cursor of the next GetMessagesSince
+    pub cursor: u64,
+}
+
+//never includes the keys themselves
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ViewingKeyInfo {
+    pub label: String,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ViewingKeysResponse {
+    pub keys: Vec<ViewingKeyInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PostageResponse {
+    pub postage: Option<Postage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MailboxSettingsResponse {
+    pub settings: MailboxSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MailboxStatus {
+    pub initialized: bool,
+    pub claimed: bool,
+    pub accepting: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ContractInfoResponse {
+    pub version: String,
+    pub storage_version: u32,
+    pub admin: HumanAddr,
+    pub status: ContractStatusLevel,
+    pub send_limit: Option<RateLimit>,
+    pub max_viewing_keys: usize,
+    pub max_compact_steps: u32,
+    pub block_size: usize,
+    pub total_mailboxes: u64,
+    pub messages_sent: u64,
+    pub messages_deleted: u64,
+}
+
+//status is None when the owner hides it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MailboxStatusResponse {
+    pub status: Option<MailboxStatus>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ResponseStatus {
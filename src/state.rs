@@ -1,29 +1,156 @@
 use std::any::type_name;
+use std::convert::TryInto;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{CanonicalAddr, Storage, HumanAddr, StdResult, StdError, ReadonlyStorage, HandleResponse};
+use cosmwasm_std::{to_vec, CanonicalAddr, Storage, HumanAddr, StdResult, StdError, ReadonlyStorage, HandleResponse, Uint128};
 use cosmwasm_storage::{singleton, singleton_read, ReadonlySingleton, Singleton, PrefixedStorage, ReadonlyPrefixedStorage};
 use secret_toolkit::storage::{AppendStore, AppendStoreMut};
 use secret_toolkit::serialization::{Bincode2, Serde};
+use secret_toolkit_crypto::sha_256;
 use serde::de::DeserializeOwned;
 
-use crate::viewing_key::ViewingKey;
+use crate::viewing_key::{ViewingKey, VIEWING_KEY_SIZE};
 
-pub static CONFIG_KEY: &[u8] = b"config"; //this is for initializing the contract 
+pub static CONFIG_KEY: &[u8] = b"configv1"; //this is for initializing the contract 
+//where the original layout kept State, before records were versioned. read_config still understands it
+pub static LEGACY_CONFIG_KEY: &[u8] = b"config";
+//storage layout version the contract-wide records are at. Contracts from before versioning don't have one
+pub static STORAGE_VERSION_KEY: &[u8] = b"storageversion";
+pub const CURRENT_STORAGE_VERSION: u32 = 1;
 pub const PREFIX_MSGS_RECEIVED: &[u8] = b"messages_received"; //A prefix to make namespace longer
+pub const PREFIX_MAILBOX_HEADER: &[u8] = b"header";
+//headers from before they were versioned, read until the collection is next written to
+pub const PREFIX_LEGACY_MAILBOX_HEADER: &[u8] = b"mailboxheader";
+pub const PREFIX_SENDER_INDEX: &[u8] = b"senderindex";
+pub const PREFIX_TOMBSTONES: &[u8] = b"deletions";
+//tombstones from before they were versioned. Nothing is added to these any more, and they are all older than the rest
+pub const PREFIX_LEGACY_TOMBSTONES: &[u8] = b"tombstones";
+pub const PREFIX_READ_GRANTS: &[u8] = b"readaccess";
+//read grants from before they were versioned
+pub const PREFIX_LEGACY_READ_GRANTS: &[u8] = b"readgrants";
+pub const PREFIX_SEND_GRANTS: &[u8] = b"sendaccess";
+//send grants from before they were versioned
+pub const PREFIX_LEGACY_SEND_GRANTS: &[u8] = b"sendgrants";
+pub const PREFIX_LABELS: &[u8] = b"labels";
+pub const PREFIX_MESSAGE_META: &[u8] = b"meta";
+//message meta from before it was versioned, read until the message is next updated
+pub const PREFIX_LEGACY_MESSAGE_META: &[u8] = b"messagemeta";
+pub const PREFIX_PINNED: &[u8] = b"pinned";
+pub const PREFIX_FORWARDING_RULES: &[u8] = b"forwardingrules";
+pub const PREFIX_MAILBOX_HOME: &[u8] = b"mailboxhome";
+pub const PREFIX_MAILBOX_TRANSFERS: &[u8] = b"mailboxtransfers";
+pub const PREFIX_GRANTEES: &[u8] = b"grantees";
+pub const PREFIX_CLOSED: &[u8] = b"closed";
+pub const PREFIX_DIGESTS: &[u8] = b"digests";
+//height of the latest block the contract handled a message in. Queries don't get an Env, so this is how they tell time
+pub static LAST_BLOCK_KEY: &[u8] = b"lastblock";
 
-pub const PREFIX_VIEWING_KEY: &[u8] = b"viewingkey";
+pub const PREFIX_VIEWING_KEYS: &[u8] = b"viewingkeys";
+//viewing keys from before they were versioned, either a single hash or an unversioned list of them
+pub const PREFIX_LEGACY_VIEWING_KEY: &[u8] = b"viewingkey";
+pub const PREFIX_MAILBOX_SETTINGS: &[u8] = b"settings";
+//settings from before they were versioned, read until the owner next saves theirs
+pub const PREFIX_LEGACY_MAILBOX_SETTINGS: &[u8] = b"mailboxsettings";
+pub const PREFIX_SEND_WINDOW: &[u8] = b"sendwindow";
+pub const PREFIX_INBOUND_WINDOW: &[u8] = b"inboundwindow";
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     pub owner: CanonicalAddr, 
     pub contract: HumanAddr, 
+    //rolls forward to the PRNG's next output every time a viewing key is generated
     pub prng_seed: Vec<u8>,
+    //never changes once set, so the receipt for a message stays the same
+    pub receipt_secret: Vec<u8>,
+    //admin-configured cap on how many messages one sender can send per window
+    pub send_limit: Option<RateLimit>,
+    pub status: ContractStatusLevel,
+    //running totals for ContractInfo
+    pub total_mailboxes: u64,
+    pub messages_sent: u64,
+    pub messages_deleted: u64,
+}
+
+// Lets the admin stop the contract in an emergency, SNIP-20 style
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContractStatusLevel {
+    NormalRun,
+    //nothing can be delivered, everything else still works
+    StopSending,
+    //only the admin's SetContractStatus goes through
+    StopAll,
+}
+
+// At most max_messages per window of block heights
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RateLimit {
+    pub max_messages: u32,
+    pub window: u64,
+}
+
+// How many messages have been counted since the window opened at block height start
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct SendWindow {
+    pub start: u64,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredSendWindow {
+    V1(SendWindow),
+}
+
+impl From<StoredSendWindow> for SendWindow {
+    fn from(stored: StoredSendWindow) -> Self {
+        match stored {
+            StoredSendWindow::V1(window) => window,
+        }
+    }
+}
+
+// SendWindow as it was stored before being versioned, unversioned under LEGACY_SEND_WINDOW_KEY
+#[derive(Serialize, Deserialize)]
+pub struct LegacySendWindow {
+    pub start: u64,
+    pub count: u32,
+}
+
+impl From<LegacySendWindow> for SendWindow {
+    fn from(legacy: LegacySendWindow) -> Self {
+        SendWindow { start: legacy.start, count: legacy.count }
+    }
+}
+
+pub const SEND_WINDOW_KEY: &[u8] = b"span";
+pub const LEGACY_SEND_WINDOW_KEY: &[u8] = b"window";
+
+pub fn write_send_window<S: Storage>(store: &mut S, namespace: &[&[u8]], window: &SendWindow) -> StdResult<()> {
+    let mut window_store = PrefixedStorage::multilevel(namespace, store);
+    save(&mut window_store, SEND_WINDOW_KEY, &StoredSendWindow::V1(window.clone()))?;
+    window_store.remove(LEGACY_SEND_WINDOW_KEY);
+    Ok(())
+}
+
+pub fn read_send_window<S: ReadonlyStorage>(store: &S, namespace: &[&[u8]]) -> StdResult<SendWindow> {
+    let window_store = ReadonlyPrefixedStorage::multilevel(namespace, store);
+    if let Some(bytes) = window_store.get(SEND_WINDOW_KEY) {
+        return Ok(Bincode2::deserialize::<StoredSendWindow>(&bytes)?.into());
+    }
+    match window_store.get(LEGACY_SEND_WINDOW_KEY) {
+        Some(bytes) => Ok(Bincode2::deserialize::<LegacySendWindow>(&bytes)?.into()),
+        None => Ok(SendWindow::default()),
+    }
+}
 
+pub fn remove_send_window<S: Storage>(store: &mut S, namespace: &[&[u8]]) {
+    let mut window_store = PrefixedStorage::multilevel(namespace, store);
+    window_store.remove(SEND_WINDOW_KEY);
+    window_store.remove(LEGACY_SEND_WINDOW_KEY);
 }
 
-pub fn config<S: Storage>(storage: &mut S) -> Singleton<S, State> {
+pub fn config<S: Storage>(storage: &mut S) -> Singleton<'_, S, State> {
     singleton(storage, CONFIG_KEY)
 }
 
@@ -40,17 +167,344 @@ pub fn load<T: DeserializeOwned, S: ReadonlyStorage>(storage: &S, key: &[u8]) ->
     )
 }
 
-pub fn config_read<S: Storage>(storage: &S) -> ReadonlySingleton<S, State> {
+pub fn config_read<S: Storage>(storage: &S) -> ReadonlySingleton<'_, S, State> {
     singleton_read(storage, CONFIG_KEY)
 }
-pub fn write_viewing_key<S: Storage>(store: &mut S, owner: &CanonicalAddr, key: &ViewingKey) {
-    let mut user_key_store = PrefixedStorage::new(PREFIX_VIEWING_KEY, store);
-    user_key_store.set(owner.as_slice(), &key.to_hashed());
+
+/*Stored records are wrapped in an enum with a variant per version, so changing a struct means adding a variant 
+instead of making every existing blob unreadable. Reading converts whatever version was stored into the current one, 
+and writing always stores the current one, so records upgrade themselves the next time they are written*/
+#[derive(Serialize, Deserialize)]
+pub enum StoredState {
+    V1(StateV1),
+    V2(State),
+}
+
+impl From<StoredState> for State {
+    fn from(stored: StoredState) -> Self {
+        match stored {
+            StoredState::V1(state) => State {
+                receipt_secret: receipt_secret_from(&state.prng_seed),
+                owner: state.owner,
+                contract: state.contract,
+                prng_seed: state.prng_seed,
+                send_limit: state.send_limit,
+                status: state.status,
+                total_mailboxes: state.total_mailboxes,
+                messages_sent: state.messages_sent,
+                messages_deleted: state.messages_deleted,
+            },
+            StoredState::V2(state) => state,
+        }
+    }
+}
+
+/*State from before receipts had a secret of their own. The one derived from the seed on upgrade is saved with the next
+write of the config, which every delivery makes before handing out a receipt, so it doesn't change after that*/
+#[derive(Serialize, Deserialize)]
+pub struct StateV1 {
+    pub owner: CanonicalAddr,
+    pub contract: HumanAddr,
+    pub prng_seed: Vec<u8>,
+    pub send_limit: Option<RateLimit>,
+    pub status: ContractStatusLevel,
+    pub total_mailboxes: u64,
+    pub messages_sent: u64,
+    pub messages_deleted: u64,
+}
+
+pub fn receipt_secret_from(prng_seed: &[u8]) -> Vec<u8> {
+    sha_256(&[prng_seed, b"receipts"].concat()).to_vec()
+}
+
+// State as the original layout stored it, unversioned under LEGACY_CONFIG_KEY
+#[derive(Serialize, Deserialize)]
+pub struct LegacyState {
+    pub owner: CanonicalAddr,
+    pub contract: HumanAddr,
+    pub prng_seed: Vec<u8>,
+}
+
+impl From<LegacyState> for State {
+    fn from(legacy: LegacyState) -> Self {
+        State {
+            owner: legacy.owner,
+            contract: legacy.contract,
+            receipt_secret: receipt_secret_from(&legacy.prng_seed),
+            prng_seed: legacy.prng_seed,
+            send_limit: None,
+            status: ContractStatusLevel::NormalRun,
+            total_mailboxes: 0,
+            messages_sent: 0,
+            messages_deleted: 0,
+        }
+    }
+}
+
+pub fn read_config<S: ReadonlyStorage>(storage: &S) -> StdResult<State> {
+    match storage.get(CONFIG_KEY) {
+        Some(bytes) => Ok(Bincode2::deserialize::<StoredState>(&bytes)?.into()),
+        None => Ok(load::<LegacyState, _>(storage, LEGACY_CONFIG_KEY)?.into()),
+    }
+}
+
+pub fn write_config<S: Storage>(storage: &mut S, state: &State) -> StdResult<()> {
+    save(storage, CONFIG_KEY, &StoredState::V2(state.clone()))?;
+    storage.remove(LEGACY_CONFIG_KEY);
+    Ok(())
+}
+
+pub fn read_storage_version<S: ReadonlyStorage>(storage: &S) -> StdResult<u32> {
+    match storage.get(STORAGE_VERSION_KEY) {
+        Some(_) => load(storage, STORAGE_VERSION_KEY),
+        None => Ok(0),
+    }
+}
+
+pub fn write_storage_version<S: Storage>(storage: &mut S, version: u32) -> StdResult<()> {
+    save(storage, STORAGE_VERSION_KEY, &version)
+}
+//most viewing keys one user can hold at a time
+pub const MAX_VIEWING_KEYS: usize = 16;
+pub const DEFAULT_VIEWING_KEY_LABEL: &str = "default";
+
+// One of a user's viewing keys. Only the hash of the key is ever stored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ViewingKeyRecord {
+    pub label: String,
+    pub hashed: Vec<u8>,
+    //block height the key was created at
+    pub created_at: u64,
+}
+
+//adds key under label, replacing any key the user already has with that label
+pub fn write_viewing_key<S: Storage>(
+    store: &mut S,
+    owner: &CanonicalAddr,
+    label: &str,
+    key: &ViewingKey,
+    height: u64,
+) -> StdResult<()> {
+    let mut keys = read_viewing_keys(store, owner)?;
+    keys.retain(|record| record.label != label);
+    if keys.len() >= MAX_VIEWING_KEYS {
+        return Err(StdError::generic_err(format!(
+            "You can hold at most {} viewing keys. Revoke one first", MAX_VIEWING_KEYS
+        )));
+    }
+    keys.push(ViewingKeyRecord { label: label.to_string(), hashed: key.to_hashed().to_vec(), created_at: height });
+    write_viewing_keys(store, owner, &keys)
+}
+
+//returns whether a key with that label existed
+pub fn revoke_viewing_key<S: Storage>(store: &mut S, owner: &CanonicalAddr, label: &str) -> StdResult<bool> {
+    let mut keys = read_viewing_keys(store, owner)?;
+    let count = keys.len();
+    keys.retain(|record| record.label != label);
+    if keys.len() == count {
+        return Ok(false);
+    }
+    write_viewing_keys(store, owner, &keys)?;
+    Ok(true)
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredViewingKeys {
+    V1(Vec<ViewingKeyRecord>),
+}
+
+impl From<StoredViewingKeys> for Vec<ViewingKeyRecord> {
+    fn from(stored: StoredViewingKeys) -> Self {
+        match stored {
+            StoredViewingKeys::V1(keys) => keys,
+        }
+    }
+}
+
+// ViewingKeyRecord as it was stored before being versioned, in an unversioned list under PREFIX_LEGACY_VIEWING_KEY
+#[derive(Serialize, Deserialize)]
+pub struct LegacyViewingKeyRecord {
+    pub label: String,
+    pub hashed: Vec<u8>,
+    pub created_at: u64,
+}
+
+impl From<LegacyViewingKeyRecord> for ViewingKeyRecord {
+    fn from(legacy: LegacyViewingKeyRecord) -> Self {
+        ViewingKeyRecord { label: legacy.label, hashed: legacy.hashed, created_at: legacy.created_at }
+    }
+}
+
+//replaces all of owner's keys. The legacy entry goes either way
+pub fn write_viewing_keys<S: Storage>(store: &mut S, owner: &CanonicalAddr, keys: &[ViewingKeyRecord]) -> StdResult<()> {
+    PrefixedStorage::new(PREFIX_LEGACY_VIEWING_KEY, store).remove(owner.as_slice());
+    let mut user_key_store = PrefixedStorage::new(PREFIX_VIEWING_KEYS, store);
+    if keys.is_empty() {
+        user_key_store.remove(owner.as_slice());
+        return Ok(());
+    }
+    save(&mut user_key_store, owner.as_slice(), &StoredViewingKeys::V1(keys.to_vec()))
+}
+
+pub fn read_viewing_keys<S: ReadonlyStorage>(store: &S, owner: &CanonicalAddr) -> StdResult<Vec<ViewingKeyRecord>> {
+    let user_key_store = ReadonlyPrefixedStorage::new(PREFIX_VIEWING_KEYS, store);
+    if let Some(bytes) = user_key_store.get(owner.as_slice()) {
+        return Ok(Bincode2::deserialize::<StoredViewingKeys>(&bytes)?.into());
+    }
+    let legacy_store = ReadonlyPrefixedStorage::new(PREFIX_LEGACY_VIEWING_KEY, store);
+    match legacy_store.get(owner.as_slice()) {
+        //users used to hold a single key, stored as nothing but its hash
+        Some(bytes) if bytes.len() == VIEWING_KEY_SIZE => Ok(vec![ViewingKeyRecord {
+            label: DEFAULT_VIEWING_KEY_LABEL.to_string(),
+            hashed: bytes,
+            created_at: 0,
+        }]),
+        Some(bytes) => {
+            let legacy: Vec<LegacyViewingKeyRecord> = Bincode2::deserialize(&bytes)?;
+            Ok(legacy.into_iter().map(ViewingKeyRecord::from).collect())
+        }
+        None => Ok(vec![]),
+    }
+}
+
+// Price a recipient charges strangers for every message delivered to their collection
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Postage {
+    Native { denom: String, amount: Uint128 },
+    Snip20 { contract: HumanAddr, code_hash: String, amount: Uint128 },
+}
+
+// Per-user mailbox settings, keyed by the same address as the user's collection
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct MailboxSettings {
+    #[serde(default)]
+    pub postage: Option<Postage>,
+    //senders on the allow list never pay postage
+    #[serde(default)]
+    pub allow_list: Vec<HumanAddr>,
+    //how many messages any one sender may deliver to this collection per window
+    #[serde(default)]
+    pub inbound_limit: Option<RateLimit>,
+    //MailboxStatus reports nothing about this address
+    #[serde(default)]
+    pub hide_status: bool,
+    //when false only senders on the allow list can deliver
+    #[serde(default = "accept_from_anyone_default")]
+    pub accept_from_anyone: bool,
+    //senders get the id their message was delivered under back in the handle response
+    #[serde(default)]
+    pub receipts: bool,
+    //only the newest this many messages can be read, older ones count as deleted
+    #[serde(default)]
+    pub retention: Option<u32>,
+    //messages are delivered to this address instead
+    #[serde(default)]
+    pub auto_forward: Option<HumanAddr>,
+    #[serde(default)]
+    pub notifications: NotificationPrefs,
+}
+
+fn accept_from_anyone_default() -> bool {
+    true
+}
+
+impl Default for MailboxSettings {
+    fn default() -> Self {
+        MailboxSettings {
+            postage: None,
+            allow_list: vec![],
+            inbound_limit: None,
+            hide_status: false,
+            accept_from_anyone: true,
+            receipts: false,
+            retention: None,
+            auto_forward: None,
+            notifications: NotificationPrefs::default(),
+        }
+    }
+}
+
+/*For whatever notifies users off chain. The contract only acts on on_delivery: a delivery to a mailbox that set it 
+gets a "notify" log entry naming the mailbox, so the frontend that sent it knows to ping the recipient*/
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+pub struct NotificationPrefs {
+    #[serde(default)]
+    pub on_delivery: bool,
+    //free-form hints for the notifier, e.g. "email" or "push"
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+impl MailboxSettings {
+    pub fn is_allowed(&self, sender: &HumanAddr) -> bool {
+        self.allow_list.iter().any(|allowed| allowed == sender)
+    }
+
+    //whether sender may deliver here at all, leaving rate limits aside
+    pub fn accepts_from(&self, sender: &HumanAddr) -> bool {
+        self.accept_from_anyone || self.is_allowed(sender)
+    }
+
+    //false once nobody at all can get a message in
+    pub fn accepts_messages(&self) -> bool {
+        let strangers = self.accept_from_anyone && self.inbound_limit.as_ref().map_or(true, |limit| limit.max_messages > 0);
+        strangers || !self.allow_list.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredMailboxSettings {
+    V1(MailboxSettings),
+}
+
+impl From<StoredMailboxSettings> for MailboxSettings {
+    fn from(stored: StoredMailboxSettings) -> Self {
+        match stored {
+            StoredMailboxSettings::V1(settings) => settings,
+        }
+    }
+}
+
+// MailboxSettings as they were stored before being versioned, unversioned under PREFIX_LEGACY_MAILBOX_SETTINGS
+#[derive(Serialize, Deserialize)]
+pub struct LegacyMailboxSettings {
+    pub postage: Option<Postage>,
+    pub allow_list: Vec<HumanAddr>,
+    pub inbound_limit: Option<RateLimit>,
+    pub hide_status: bool,
+}
+
+impl From<LegacyMailboxSettings> for MailboxSettings {
+    fn from(legacy: LegacyMailboxSettings) -> Self {
+        MailboxSettings {
+            postage: legacy.postage,
+            allow_list: legacy.allow_list,
+            inbound_limit: legacy.inbound_limit,
+            hide_status: legacy.hide_status,
+            ..Default::default()
+        }
+    }
+}
+
+//the legacy entry goes once the settings are saved in the current layout
+pub fn write_mailbox_settings<S: Storage>(store: &mut S, owner: &HumanAddr, settings: &MailboxSettings) -> StdResult<()> {
+    let mut settings_store = PrefixedStorage::new(PREFIX_MAILBOX_SETTINGS, store);
+    save(&mut settings_store, owner.0.as_bytes(), &StoredMailboxSettings::V1(settings.clone()))?;
+    remove_entry(store, PREFIX_LEGACY_MAILBOX_SETTINGS, owner);
+    Ok(())
 }
 
-pub fn read_viewing_key<S: Storage>(store: &S, owner: &CanonicalAddr) -> Option<Vec<u8>> {
-    let user_key_store = ReadonlyPrefixedStorage::new(PREFIX_VIEWING_KEY, store);
-    user_key_store.get(owner.as_slice())
+pub fn read_mailbox_settings<S: ReadonlyStorage>(store: &S, owner: &HumanAddr) -> StdResult<MailboxSettings> {
+    let settings_store = ReadonlyPrefixedStorage::new(PREFIX_MAILBOX_SETTINGS, store);
+    if let Some(bytes) = settings_store.get(owner.0.as_bytes()) {
+        return Ok(Bincode2::deserialize::<StoredMailboxSettings>(&bytes)?.into());
+    }
+    let legacy_store = ReadonlyPrefixedStorage::new(PREFIX_LEGACY_MAILBOX_SETTINGS, store);
+    match legacy_store.get(owner.0.as_bytes()) {
+        Some(bytes) => Ok(Bincode2::deserialize::<LegacyMailboxSettings>(&bytes)?.into()),
+        None => Ok(MailboxSettings::default()),
+    }
 }
 
 // HandleMsg Message
@@ -59,16 +513,44 @@ pub struct Message{
     
     contents: String, //contents will be a message that Erin will customize on the frontend.
     //Front end will have a way of connecting JACKAL-storage with JACKAL-filesharing in order for this to work.
-    owner: String
+    owner: String,
+    //block time (seconds since epoch) the message was delivered at
+    timestamp: u64,
+    //sequence number the collection assigned to this message when it was appended
+    id: u64,
+    //set on copies, to whoever forwarded the message here
+    forwarded_by: Option<String>,
+    //what the forwarder had to say about it
+    note: Option<String>,
+    //set on copies, to the block time the message was first delivered at. timestamp is when the copy was
+    original_timestamp: Option<u64>,
 
 }
 
 impl Message {
 
-    pub fn new(contents: String, owner: String) -> Self {
+    pub fn new(contents: String, owner: String, timestamp: u64) -> Self {
         Self {
             contents,
             owner,
+            timestamp,
+            id: 0,
+            forwarded_by: None,
+            note: None,
+            original_timestamp: None,
+        }
+    }
+
+    /*a copy of this message for another collection, keeping its sender. The copy is stamped with the time it is delivered
+    at, since reads expect timestamps in a collection to never go down, and remembers the time the original was*/
+    pub fn forwarded(&self, by: &HumanAddr, note: Option<String>, timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            id: 0,
+            forwarded_by: Some(by.to_string()),
+            note,
+            original_timestamp: Some(self.original_timestamp.unwrap_or(self.timestamp)),
+            ..self.clone()
         }
     }
 
@@ -80,44 +562,963 @@ impl Message {
         &self.owner
     }
 
-    pub fn store_message<S:Storage>(&self, store: &mut S, to: &HumanAddr) -> StdResult<()>{
-        append_message(store, &self, to)
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn get_forwarded_by(&self) -> Option<&str> {
+        self.forwarded_by.as_deref()
+    }
+
+    pub fn get_note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    pub fn get_original_timestamp(&self) -> Option<u64> {
+        self.original_timestamp
+    }
+
+    //returns the id the message was stored under, and whether this started a new collection for to
+    pub fn store_message<S:Storage>(&self, store: &mut S, to: &HumanAddr) -> StdResult<(u64, bool)>{
+        append_message(store, self, to)
     }
 
     //returns length of the collection that this message belongs in. Used for testing
     pub fn len<S: ReadonlyStorage>(storage: &S,
                                    for_address: &HumanAddr) -> u32 {
+        let header = match read_mailbox_header(storage, for_address) {
+            Ok(Some(header)) => header,
+            _ => return 0,
+        };
+        let home = match collection_home(storage, for_address) {
+            Ok(home) => home,
+            Err(_) => return 0,
+        };
         let store = ReadonlyPrefixedStorage::multilevel(
-            &[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes()],
+            &[PREFIX_MSGS_RECEIVED, home.as_bytes(), &header.generation.to_be_bytes()],
             storage
         );
-        let store = AppendStore::<Message, _, _>::attach(&store);
-        let store = if let Some(result) = store {
-            if result.is_err() {
-                return 0;
-            } else {
-                result.unwrap()
-            }
-        } else {
-            return 0;
-        };
+        let store = AppendStore::<StoredMessage, _, _>::attach(&store);
+        match store {
+            Some(Ok(store)) => store.len(),
+            _ => 0,
+        }
+    }
+}
+
+// How messages are stored, see StoredState
+#[derive(Serialize, Deserialize)]
+pub enum StoredMessage {
+    V1(MessageV1),
+    V2(MessageV2),
+    V3(MessageV3),
+    V4(Message),
+}
+
+impl From<StoredMessage> for Message {
+    fn from(stored: StoredMessage) -> Self {
+        match stored {
+            StoredMessage::V1(message) => Message {
+                contents: message.contents,
+                owner: message.owner,
+                timestamp: message.timestamp,
+                id: message.id,
+                forwarded_by: None,
+                note: None,
+                original_timestamp: None,
+            },
+            StoredMessage::V2(message) => Message {
+                contents: message.contents,
+                owner: message.owner,
+                timestamp: message.timestamp,
+                id: message.id,
+                forwarded_by: message.forwarded_by,
+                note: None,
+                original_timestamp: None,
+            },
+            StoredMessage::V3(message) => Message {
+                contents: message.contents,
+                owner: message.owner,
+                timestamp: message.timestamp,
+                id: message.id,
+                forwarded_by: message.forwarded_by,
+                note: message.note,
+                original_timestamp: None,
+            },
+            StoredMessage::V4(message) => message,
+        }
+    }
+}
+
+// Message before forwarding was added
+#[derive(Serialize, Deserialize)]
+pub struct MessageV1 {
+    pub contents: String,
+    pub owner: String,
+    pub timestamp: u64,
+    pub id: u64,
+}
+
+// Message before forwarding notes were added
+#[derive(Serialize, Deserialize)]
+pub struct MessageV2 {
+    pub contents: String,
+    pub owner: String,
+    pub timestamp: u64,
+    pub id: u64,
+    pub forwarded_by: Option<String>,
+}
+
+// Message before copies kept the original timestamp apart
+#[derive(Serialize, Deserialize)]
+pub struct MessageV3 {
+    pub contents: String,
+    pub owner: String,
+    pub timestamp: u64,
+    pub id: u64,
+    pub forwarded_by: Option<String>,
+    pub note: Option<String>,
+}
+
+// Message as the original layout stored it
+#[derive(Serialize, Deserialize)]
+pub struct LegacyMessage {
+    pub contents: String,
+    pub owner: String,
+}
 
-        return store.len();
+// Every collection has a header holding its owner and which generation of the collection is live.
+// Messages of generation g live under [PREFIX_MSGS_RECEIVED, address, g], so bumping the generation empties the
+// collection in a single write. Generations below `compacted` have been fully reclaimed by Compact.
+// `sequence` is the last sequence number handed out. Every appended message and every deletion takes the next one,
+// so clients can sync everything that happened after the last sequence number they saw.
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct MailboxHeader {
+    pub owner: String,
+    pub generation: u32,
+    pub compacted: u32,
+    pub sequence: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredMailboxHeader {
+    V1(MailboxHeader),
+}
+
+impl From<StoredMailboxHeader> for MailboxHeader {
+    fn from(stored: StoredMailboxHeader) -> Self {
+        match stored {
+            StoredMailboxHeader::V1(header) => header,
+        }
+    }
+}
+
+// MailboxHeader as it was stored before being versioned, unversioned under PREFIX_LEGACY_MAILBOX_HEADER
+#[derive(Serialize, Deserialize)]
+pub struct LegacyMailboxHeader {
+    pub owner: String,
+    pub generation: u32,
+    pub compacted: u32,
+    pub sequence: u64,
+}
+
+impl From<LegacyMailboxHeader> for MailboxHeader {
+    fn from(legacy: LegacyMailboxHeader) -> Self {
+        MailboxHeader {
+            owner: legacy.owner,
+            generation: legacy.generation,
+            compacted: legacy.compacted,
+            sequence: legacy.sequence,
+        }
+    }
+}
+
+// Reports that every message with an id up to deleted_through was deleted, at sequence number seq. Pinned messages
+// listed in except survived it
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct Tombstone {
+    pub seq: u64,
+    pub deleted_through: u64,
+    #[serde(default)]
+    pub except: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredTombstone {
+    V1(Tombstone),
+}
+
+impl From<StoredTombstone> for Tombstone {
+    fn from(stored: StoredTombstone) -> Self {
+        match stored {
+            StoredTombstone::V1(tombstone) => tombstone,
+        }
+    }
+}
+
+// Tombstone as it was stored before being versioned, in its own AppendStore under PREFIX_LEGACY_TOMBSTONES
+#[derive(Serialize, Deserialize)]
+pub struct LegacyTombstone {
+    pub seq: u64,
+    pub deleted_through: u64,
+}
+
+impl From<LegacyTombstone> for Tombstone {
+    fn from(legacy: LegacyTombstone) -> Self {
+        Tombstone {
+            seq: legacy.seq,
+            deleted_through: legacy.deleted_through,
+            except: vec![],
+        }
+    }
+}
+
+pub fn append_tombstone<S: Storage>(store: &mut S, for_address: &HumanAddr, tombstone: &Tombstone) -> StdResult<()> {
+    let home = collection_home(store, for_address)?;
+    let mut store = PrefixedStorage::multilevel(&[PREFIX_TOMBSTONES, home.as_bytes()], store);
+    let mut store = AppendStoreMut::attach_or_create(&mut store)?;
+    store.push(&StoredTombstone::V1(tombstone.clone()))
+}
+
+/*The storage a collection's messages, sender index, tombstones and message meta live under. That is the owner's own
+address unless the collection was handed over by a mailbox transfer, in which case it stays where it was and the new
+owner's home points at it*/
+pub fn collection_home<S: ReadonlyStorage>(store: &S, for_address: &HumanAddr) -> StdResult<String> {
+    let home_store = ReadonlyPrefixedStorage::new(PREFIX_MAILBOX_HOME, store);
+    match home_store.get(for_address.0.as_bytes()) {
+        Some(bytes) => Bincode2::deserialize(&bytes),
+        None => Ok(for_address.to_string()),
+    }
+}
+
+pub fn write_collection_home<S: Storage>(store: &mut S, for_address: &HumanAddr, home: &str) -> StdResult<()> {
+    let mut home_store = PrefixedStorage::new(PREFIX_MAILBOX_HOME, store);
+    save(&mut home_store, for_address.0.as_bytes(), &home)
+}
+
+pub fn remove_mailbox_header<S: Storage>(store: &mut S, for_address: &HumanAddr) {
+    remove_entry(store, PREFIX_MAILBOX_HEADER, for_address);
+    remove_entry(store, PREFIX_LEGACY_MAILBOX_HEADER, for_address);
+}
+
+//moves whatever is stored under prefix for one address over to another, replacing what the other had
+pub fn move_entry<S: Storage>(store: &mut S, prefix: &[u8], from: &HumanAddr, to: &HumanAddr) {
+    let mut prefixed = PrefixedStorage::new(prefix, store);
+    match prefixed.get(from.0.as_bytes()) {
+        Some(bytes) => {
+            prefixed.set(to.0.as_bytes(), &bytes);
+            prefixed.remove(from.0.as_bytes());
+        }
+        None => prefixed.remove(to.0.as_bytes()),
+    }
+}
+
+//the legacy entry goes once the header is saved in the current layout
+pub fn write_mailbox_header<S: Storage>(store: &mut S, for_address: &HumanAddr, header: &MailboxHeader) -> StdResult<()> {
+    let mut header_store = PrefixedStorage::new(PREFIX_MAILBOX_HEADER, store);
+    save(&mut header_store, for_address.0.as_bytes(), &StoredMailboxHeader::V1(header.clone()))?;
+    remove_entry(store, PREFIX_LEGACY_MAILBOX_HEADER, for_address);
+    Ok(())
+}
+
+//both entries are read every time, so delivering to an address without a header doesn't take fewer reads, see append_message
+pub fn read_mailbox_header<S: ReadonlyStorage>(store: &S, for_address: &HumanAddr) -> StdResult<Option<MailboxHeader>> {
+    let current = ReadonlyPrefixedStorage::new(PREFIX_MAILBOX_HEADER, store).get(for_address.0.as_bytes());
+    let legacy = ReadonlyPrefixedStorage::new(PREFIX_LEGACY_MAILBOX_HEADER, store).get(for_address.0.as_bytes());
+    match (current, legacy) {
+        (Some(bytes), _) => Ok(Some(Bincode2::deserialize::<StoredMailboxHeader>(&bytes)?.into())),
+        (None, Some(bytes)) => Ok(Some(Bincode2::deserialize::<LegacyMailboxHeader>(&bytes)?.into())),
+        (None, None) => Ok(None),
+    }
+}
+
+/*Appends a message exported from another collection, keeping its id and timestamp. The collection's sequence jumps 
+ahead to it, so ids keep growing as long as messages are imported in the order they were exported and nothing else 
+arrives between. Reads expect timestamps to never go down either, so a message older than the last one is refused*/
+pub fn import_message<S: Storage>(store: &mut S, message: &Message, for_address: &HumanAddr) -> StdResult<bool> {
+    let existing = read_mailbox_header(store, for_address)?;
+    let created = existing.is_none();
+    let mut header = existing.unwrap_or_else(|| empty_header(for_address));
+    let older = |what: &str| StdError::generic_err(format!(
+        "Message {} is older than {} {} already has. Import pages in order", message.id, what, for_address
+    ));
+    if message.id <= header.sequence {
+        return Err(older("what"));
+    }
+    let home = collection_home(store, for_address)?;
+    let messages = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_MSGS_RECEIVED, home.as_bytes(), &header.generation.to_be_bytes()],
+        &*store
+    );
+    if let Some(messages) = AppendStore::<StoredMessage, _, _>::attach(&messages) {
+        let messages = messages?;
+        if !messages.is_empty() && Message::from(messages.get_at(messages.len() - 1)?).timestamp > message.timestamp {
+            return Err(older("the last message"));
+        }
     }
+    header.sequence = message.id - 1;
+    write_mailbox_header(store, for_address, &header)?;
+
+    append_message(store, message, for_address)?;
+    Ok(created)
 }
 
 //see notes below regarding AppendStore
+/*Delivery runs the exact same storage reads and writes whether or not for_address has used the system before, so the
+gas used doesn't tell anyone watching the chain. A missing header is treated as an empty one and written either way.
+The one thing left is the read itself: the chain charges per byte read, so not finding a header is a few gas cheaper.
+Returns the id the message got, and whether the header was missing, i.e. this started a new collection*/
 pub fn append_message<S: Storage> (
     store: &mut S,
     message: &Message,
     for_address: &HumanAddr, 
-) -> StdResult<()>{
-    
-    let option_error_message = format!("Provided storage doesn't seem like an AppendStore");            
-    let mut store = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes()], store);
-    let mut store = AppendStoreMut::attach(&mut store).unwrap_or(Err(StdError::generic_err(option_error_message)))?;
+) -> StdResult<(u64, bool)>{
     
-    store.push(message)
+    if is_closed(store, for_address) {
+        return Err(StdError::generic_err(format!("{} has closed their mailbox", for_address)));
+    }
+    //read on every delivery, for the same reason. A legacy collection only gets its header now, so it counts as new
+    let migrated = migrate_on_write(store, for_address)?;
+
+    let existing = read_mailbox_header(store, for_address)?;
+    let created = existing.is_none() || migrated;
+    let mut header = existing.unwrap_or_else(|| empty_header(for_address));
+    header.sequence += 1;
+    write_mailbox_header(store, for_address, &header)?;
+
+    let message = Message { id: header.sequence, ..message.clone() };
+    let home = collection_home(store, for_address)?;
+    push_message(store, home.as_bytes(), header.generation, &message)?;
+    Ok((message.id, created))
+}
+
+//adds message to the end of a generation, along with its sender index and digest chain entries. Returns its position
+fn push_message<S: Storage>(store: &mut S, home: &[u8], generation: u32, message: &Message) -> StdResult<u32> {
+    let generation = generation.to_be_bytes();
+    let mut messages = PrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, home, &generation], store);
+    let position = push_item(&mut messages, &StoredMessage::V4(message.clone()))?;
+
+    //the sender index lists, per sender, the positions of their messages in this generation
+    let mut index = PrefixedStorage::multilevel(&[PREFIX_SENDER_INDEX, home, &generation, message.owner.as_bytes()], store);
+    push_item(&mut index, &position)?;
+
+    let mut chain = PrefixedStorage::multilevel(&[PREFIX_DIGESTS, home, &generation], store);
+    let previous = match chain.get(DIGEST_HEAD_KEY) {
+        Some(bytes) => bytes,
+        None => EMPTY_DIGEST.to_vec(),
+    };
+    let link = chain_link(&previous, message)?;
+    chain.set(DIGEST_HEAD_KEY, &link);
+    push_item(&mut chain, &link)?;
+    Ok(position)
+}
+
+/*Every generation keeps a hash chain over its messages, so clients can check their cached copy against a single value.
+The chain starts out as EMPTY_DIGEST and each message moves it on to sha256(previous ++ message as JSON). Next to the
+value after every position, an AppendStore under [PREFIX_DIGESTS, home, generation], the latest value is kept under
+DIGEST_HEAD_KEY so appending doesn't need to know whether the generation is empty*/
+pub const EMPTY_DIGEST: [u8; 32] = [0; 32];
+pub const DIGEST_HEAD_KEY: &[u8] = b"head";
+
+pub fn chain_link(previous: &[u8], message: &Message) -> StdResult<[u8; 32]> {
+    let mut data = previous.to_vec();
+    data.extend_from_slice(&to_vec(message)?);
+    Ok(sha_256(&data))
+}
+
+/*Pushes item onto the AppendStore in store and returns its position. AppendStoreMut::attach_or_create writes an extra
+length entry when it creates the store, so this does the push by hand with one read and two writes every time. The
+layout is the same, so AppendStore can read it back*/
+fn push_item<T: Serialize, S: Storage>(store: &mut S, item: &T) -> StdResult<u32> {
+    let len = match store.get(b"len") {
+        Some(bytes) => u32::from_be_bytes(
+            bytes.as_slice().try_into().map_err(|err| StdError::parse_err("u32", err))?
+        ),
+        None => 0,
+    };
+    store.set(&len.to_be_bytes(), &Bincode2::serialize(item)?);
+    store.set(b"len", &(len + 1).to_be_bytes());
+    Ok(len)
+}
+
+/*The original layout kept a collection as an AppendStore of LegacyMessage under [PREFIX_MSGS_RECEIVED, address], with a
+placeholder holding the owner at index 0, and no header. Such a collection is migrated by moving its messages into 
+generation 0 of the current layout, in order, and writing the header last. The legacy store's length entry stays until 
+then, so it tells us the migration isn't finished*/
+fn legacy_namespace(for_address: &HumanAddr) -> [&[u8]; 2] {
+    [PREFIX_MSGS_RECEIVED, for_address.0.as_bytes()]
+}
+
+pub fn has_legacy_collection<S: ReadonlyStorage>(store: &S, for_address: &HumanAddr) -> bool {
+    ReadonlyPrefixedStorage::multilevel(&legacy_namespace(for_address), store).get(b"len").is_some()
+}
+
+//a legacy message gets its position as its id, and 0 as its timestamp since the original layout didn't record one
+fn upgrade_legacy_message(legacy: LegacyMessage, position: u32) -> Message {
+    Message { contents: legacy.contents, owner: legacy.owner, timestamp: 0, id: position as u64, forwarded_by: None, note: None, original_timestamp: None }
+}
+
+//every message of a collection that hasn't finished migrating, the moved ones included. None if it isn't a legacy collection
+pub fn read_legacy_messages<S: ReadonlyStorage>(store: &S, for_address: &HumanAddr) -> StdResult<Option<Vec<Message>>> {
+    let legacy = ReadonlyPrefixedStorage::multilevel(&legacy_namespace(for_address), store);
+    let legacy = match AppendStore::<LegacyMessage, _, _>::attach(&legacy) {
+        Some(legacy) => legacy?,
+        None => return Ok(None),
+    };
+
+    let moved = ReadonlyPrefixedStorage::multilevel(
+        &[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes(), &0u32.to_be_bytes()],
+        store
+    );
+    let mut messages = match AppendStore::<StoredMessage, _, _>::attach(&moved) {
+        Some(moved) => moved?.iter().map(|stored| stored.map(Message::from)).collect::<StdResult<Vec<Message>>>()?,
+        None => vec![],
+    };
+    //position 0 is the placeholder
+    for position in messages.len() as u32 + 1..legacy.len() {
+        messages.push(upgrade_legacy_message(legacy.get_at(position)?, position));
+    }
+    Ok(Some(messages))
+}
+
+//most steps the first write to a legacy collection will take to migrate it. Bigger collections wait for Migrate
+pub const MAX_MIGRATE_ON_WRITE: u32 = 30;
+
+//whether for_address has a legacy collection too big to be migrated by the first write to it
+pub fn waits_for_migration<S: ReadonlyStorage>(store: &S, for_address: &HumanAddr) -> StdResult<bool> {
+    let legacy = ReadonlyPrefixedStorage::multilevel(&legacy_namespace(for_address), store);
+    match AppendStore::<LegacyMessage, _, _>::attach(&legacy) {
+        //one step per message, the placeholder's step writes the header
+        Some(legacy) => Ok(legacy?.len() > MAX_MIGRATE_ON_WRITE),
+        None => Ok(false),
+    }
+}
+
+/*Collections upgrade lazily like every other record: the first write to a legacy collection migrates it on the spot, 
+unless it is too big for that. Returns whether it migrated one*/
+pub fn migrate_on_write<S: Storage>(store: &mut S, for_address: &HumanAddr) -> StdResult<bool> {
+    if !has_legacy_collection(store, for_address) {
+        return Ok(false);
+    }
+    if waits_for_migration(store, for_address)? {
+        return Err(StdError::generic_err(format!("The collection of {} is waiting to be migrated", for_address)));
+    }
+    migrate_legacy_collection(store, for_address, MAX_MIGRATE_ON_WRITE)?;
+    Ok(true)
+}
+
+/*Moves at most limit messages of a legacy collection into the current layout. Returns how many steps it took and 
+whether the collection is done, i.e. has its header now*/
+pub fn migrate_legacy_collection<S: Storage>(store: &mut S, for_address: &HumanAddr, limit: u32) -> StdResult<(u32, bool)> {
+    let legacy_len = {
+        let legacy = ReadonlyPrefixedStorage::multilevel(&legacy_namespace(for_address), store);
+        match AppendStore::<LegacyMessage, _, _>::attach(&legacy) {
+            Some(legacy) => legacy?.len(),
+            None => return Ok((0, true)),
+        }
+    };
+    let generation = 0u32.to_be_bytes();
+    let mut steps = 0;
+
+    loop {
+        let moved = {
+            let messages = ReadonlyPrefixedStorage::multilevel(&[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes(), &generation], store);
+            match AppendStore::<StoredMessage, _, _>::attach(&messages) {
+                Some(messages) => messages?.len(),
+                None => 0,
+            }
+        };
+        if steps >= limit {
+            return Ok((steps, false));
+        }
+        steps += 1;
+
+        let position = moved + 1;
+        if position >= legacy_len {
+            break;
+        }
+
+        let message = {
+            let legacy = ReadonlyPrefixedStorage::multilevel(&legacy_namespace(for_address), store);
+            let legacy = AppendStore::<LegacyMessage, _, _>::attach(&legacy).unwrap_or_else(|| Err(StdError::not_found("legacy collection")))?;
+            upgrade_legacy_message(legacy.get_at(position)?, position)
+        };
+        push_message(store, for_address.0.as_bytes(), 0, &message)?;
+        let mut legacy = PrefixedStorage::multilevel(&legacy_namespace(for_address), store);
+        legacy.remove(&position.to_be_bytes());
+    }
+
+    //everything moved: drop the placeholder and the length entry, and start using the header
+    let mut legacy = PrefixedStorage::multilevel(&legacy_namespace(for_address), store);
+    legacy.remove(&0u32.to_be_bytes());
+    legacy.remove(b"len");
+    let header = MailboxHeader { sequence: legacy_len.saturating_sub(1) as u64, ..empty_header(for_address) };
+    write_mailbox_header(store, for_address, &header)?;
+    Ok((steps, true))
+}
+
+//pops the last item off the AppendStore in store and removes its entry, and the AppendStore itself once it is empty
+pub fn remove_last<T: Serialize + DeserializeOwned, S: Storage>(store: &mut S) -> StdResult<Option<T>> {
+    let mut collection = match AppendStoreMut::<T, _, _>::attach(store) {
+        Some(collection) => collection?,
+        None => return Ok(None),
+    };
+
+    let item = if collection.is_empty() {
+        None
+    } else {
+        //pop only shortens the collection, so the entry itself has to be removed by hand
+        let item = collection.pop()?;
+        let position = collection.len();
+        collection.storage().remove(&position.to_be_bytes());
+        Some(item)
+    };
+
+    if collection.is_empty() {
+        //"len" is the key AppendStore keeps the length of the collection under
+        collection.storage().remove(b"len");
+    }
+    Ok(item)
+}
+
+// Narrows GetMessages down to one sender and/or a range of delivery times. since is inclusive, until is exclusive.
+// label and archived look at each message's MessageMeta, so get_labelled_messages applies them on top of the rest
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+pub struct MessageFilter {
+    pub from_sender: Option<HumanAddr>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub archived: Option<bool>,
+}
+
+impl MessageFilter {
+    pub fn matches(&self, message: &Message) -> bool {
+        self.from_sender.as_ref().map_or(true, |sender| sender.0 == message.owner)
+            && self.since.map_or(true, |since| message.timestamp >= since)
+            && self.until.map_or(true, |until| message.timestamp < until)
+    }
+
+    //the filter matching only messages both filters match, or None if no message can match both
+    pub fn intersect(&self, other: &MessageFilter) -> Option<MessageFilter> {
+        let from_sender = match (&self.from_sender, &other.from_sender) {
+            (Some(mine), Some(theirs)) if mine != theirs => return None,
+            (Some(sender), _) | (_, Some(sender)) => Some(sender.clone()),
+            (None, None) => None,
+        };
+        let since = match (self.since, other.since) {
+            (Some(mine), Some(theirs)) => Some(mine.max(theirs)),
+            (since, None) | (None, since) => since,
+        };
+        let until = match (self.until, other.until) {
+            (Some(mine), Some(theirs)) => Some(mine.min(theirs)),
+            (until, None) | (None, until) => until,
+        };
+        let label = match (&self.label, &other.label) {
+            (Some(mine), Some(theirs)) if mine != theirs => return None,
+            (Some(label), _) | (_, Some(label)) => Some(label.clone()),
+            (None, None) => None,
+        };
+        let archived = match (self.archived, other.archived) {
+            (Some(mine), Some(theirs)) if mine != theirs => return None,
+            (Some(archived), _) | (_, Some(archived)) => Some(archived),
+            (None, None) => None,
+        };
+        Some(MessageFilter { from_sender, since, until, label, archived })
+    }
+}
+
+//most labels one user can create
+pub const MAX_LABELS: usize = 32;
+
+// A user's labels. Labels get an id that is never reused, so deleting a label and creating it again doesn't bring
+// back the messages it was applied to
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+pub struct LabelSet {
+    pub next_id: u32,
+    pub labels: Vec<Label>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct Label {
+    pub id: u32,
+    pub name: String,
+}
+
+impl LabelSet {
+    pub fn find(&self, name: &str) -> Option<&Label> {
+        self.labels.iter().find(|label| label.name == name)
+    }
+
+    pub fn name_of(&self, id: u32) -> Option<&str> {
+        self.labels.iter().find(|label| label.id == id).map(|label| label.name.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredLabelSet {
+    V1(LabelSet),
+}
+
+impl From<StoredLabelSet> for LabelSet {
+    fn from(stored: StoredLabelSet) -> Self {
+        match stored {
+            StoredLabelSet::V1(labels) => labels,
+        }
+    }
+}
+
+pub fn write_labels<S: Storage>(store: &mut S, owner: &HumanAddr, labels: &LabelSet) -> StdResult<()> {
+    let mut label_store = PrefixedStorage::new(PREFIX_LABELS, store);
+    save(&mut label_store, owner.0.as_bytes(), &StoredLabelSet::V1(labels.clone()))
+}
+
+pub fn read_labels<S: ReadonlyStorage>(store: &S, owner: &HumanAddr) -> StdResult<LabelSet> {
+    let label_store = ReadonlyPrefixedStorage::new(PREFIX_LABELS, store);
+    match label_store.get(owner.0.as_bytes()) {
+        Some(bytes) => Ok(Bincode2::deserialize::<StoredLabelSet>(&bytes)?.into()),
+        None => Ok(LabelSet::default()),
+    }
+}
+
+// What the recipient did with a message. Kept apart from the message so the message itself never has to be rewritten,
+// under [PREFIX_MESSAGE_META, address, generation] so DeleteAllMessages drops it along with the messages. Only pinned
+// messages have theirs copied over
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone, Default)]
+pub struct MessageMeta {
+    //ids of the labels applied. Ids of deleted labels are ignored
+    pub labels: Vec<u32>,
+    pub archived: bool,
+    pub starred: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredMessageMeta {
+    V1(MessageMeta),
+}
+
+impl From<StoredMessageMeta> for MessageMeta {
+    fn from(stored: StoredMessageMeta) -> Self {
+        match stored {
+            StoredMessageMeta::V1(meta) => meta,
+        }
+    }
+}
+
+// MessageMeta as it was stored before being versioned, unversioned under PREFIX_LEGACY_MESSAGE_META
+#[derive(Serialize, Deserialize)]
+pub struct LegacyMessageMeta {
+    pub labels: Vec<u32>,
+    pub archived: bool,
+}
+
+impl From<LegacyMessageMeta> for MessageMeta {
+    fn from(legacy: LegacyMessageMeta) -> Self {
+        MessageMeta {
+            labels: legacy.labels,
+            archived: legacy.archived,
+            starred: false,
+        }
+    }
+}
+
+pub fn write_message_meta<S: Storage>(store: &mut S, owner: &HumanAddr, generation: u32, id: u64, meta: &MessageMeta) -> StdResult<()> {
+    let home = collection_home(store, owner)?;
+    PrefixedStorage::multilevel(&[PREFIX_LEGACY_MESSAGE_META, home.as_bytes(), &generation.to_be_bytes()], store).remove(&id.to_be_bytes());
+    let mut meta_store = PrefixedStorage::multilevel(&[PREFIX_MESSAGE_META, home.as_bytes(), &generation.to_be_bytes()], store);
+    //most messages are never touched, so plain ones don't take up an entry
+    if *meta == MessageMeta::default() {
+        meta_store.remove(&id.to_be_bytes());
+        return Ok(());
+    }
+    save(&mut meta_store, &id.to_be_bytes(), &StoredMessageMeta::V1(meta.clone()))
+}
+
+pub fn read_message_meta<S: ReadonlyStorage>(store: &S, owner: &HumanAddr, generation: u32, id: u64) -> StdResult<MessageMeta> {
+    let home = collection_home(store, owner)?;
+    let meta_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_MESSAGE_META, home.as_bytes(), &generation.to_be_bytes()], store);
+    if let Some(bytes) = meta_store.get(&id.to_be_bytes()) {
+        return Ok(Bincode2::deserialize::<StoredMessageMeta>(&bytes)?.into());
+    }
+    let legacy_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_LEGACY_MESSAGE_META, home.as_bytes(), &generation.to_be_bytes()], store);
+    match legacy_store.get(&id.to_be_bytes()) {
+        Some(bytes) => Ok(Bincode2::deserialize::<LegacyMessageMeta>(&bytes)?.into()),
+        None => Ok(MessageMeta::default()),
+    }
+}
+
+//most messages one user can have pinned
+pub const MAX_PINNED: usize = 16;
+
+/*Pinned messages are copied out of the collection so they don't go away with their generation when DeleteAllMessages
+moves on to the next one. Kept in pin order, oldest pin first*/
+pub fn write_pinned<S: Storage>(store: &mut S, owner: &HumanAddr, pinned: &[Message]) -> StdResult<()> {
+    let mut pinned_store = PrefixedStorage::new(PREFIX_PINNED, store);
+    if pinned.is_empty() {
+        pinned_store.remove(owner.0.as_bytes());
+        return Ok(());
+    }
+    let stored: Vec<StoredMessage> = pinned.iter().cloned().map(StoredMessage::V4).collect();
+    save(&mut pinned_store, owner.0.as_bytes(), &stored)
+}
+
+pub fn read_pinned<S: ReadonlyStorage>(store: &S, owner: &HumanAddr) -> StdResult<Vec<Message>> {
+    let pinned_store = ReadonlyPrefixedStorage::new(PREFIX_PINNED, store);
+    match pinned_store.get(owner.0.as_bytes()) {
+        Some(bytes) => Ok(Bincode2::deserialize::<Vec<StoredMessage>>(&bytes)?.into_iter().map(Message::from).collect()),
+        None => Ok(vec![]),
+    }
+}
+
+//most forwarding rules one user can have
+pub const MAX_FORWARDING_RULES: usize = 16;
+
+// Copies messages from match_sender to forward_to once they have been delivered
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct ForwardingRule {
+    pub match_sender: HumanAddr,
+    pub forward_to: HumanAddr,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredForwardingRules {
+    V1(Vec<ForwardingRule>),
+}
+
+impl From<StoredForwardingRules> for Vec<ForwardingRule> {
+    fn from(stored: StoredForwardingRules) -> Self {
+        match stored {
+            StoredForwardingRules::V1(rules) => rules,
+        }
+    }
+}
+
+pub fn write_forwarding_rules<S: Storage>(store: &mut S, owner: &HumanAddr, rules: &[ForwardingRule]) -> StdResult<()> {
+    let mut rule_store = PrefixedStorage::new(PREFIX_FORWARDING_RULES, store);
+    save(&mut rule_store, owner.0.as_bytes(), &StoredForwardingRules::V1(rules.to_vec()))
+}
+
+pub fn read_forwarding_rules<S: ReadonlyStorage>(store: &S, owner: &HumanAddr) -> StdResult<Vec<ForwardingRule>> {
+    let rule_store = ReadonlyPrefixedStorage::new(PREFIX_FORWARDING_RULES, store);
+    match rule_store.get(owner.0.as_bytes()) {
+        Some(bytes) => Ok(Bincode2::deserialize::<StoredForwardingRules>(&bytes)?.into()),
+        None => Ok(vec![]),
+    }
+}
+
+pub fn write_mailbox_transfer<S: Storage>(store: &mut S, owner: &HumanAddr, new_owner: &HumanAddr) -> StdResult<()> {
+    let mut transfer_store = PrefixedStorage::new(PREFIX_MAILBOX_TRANSFERS, store);
+    save(&mut transfer_store, owner.0.as_bytes(), new_owner)
+}
+
+pub fn read_mailbox_transfer<S: ReadonlyStorage>(store: &S, owner: &HumanAddr) -> StdResult<Option<HumanAddr>> {
+    let transfer_store = ReadonlyPrefixedStorage::new(PREFIX_MAILBOX_TRANSFERS, store);
+    transfer_store
+        .get(owner.0.as_bytes())
+        .map(|bytes| Bincode2::deserialize(&bytes))
+        .transpose()
+}
+
+pub fn remove_mailbox_transfer<S: Storage>(store: &mut S, owner: &HumanAddr) {
+    let mut transfer_store = PrefixedStorage::new(PREFIX_MAILBOX_TRANSFERS, store);
+    transfer_store.remove(owner.0.as_bytes());
+}
+
+/*Set while a mailbox is being closed and kept afterwards, so deliveries are turned away instead of quietly starting a
+new collection. InitAddress clears it*/
+pub fn set_closed<S: Storage>(store: &mut S, for_address: &HumanAddr, closed: bool) {
+    let mut closed_store = PrefixedStorage::new(PREFIX_CLOSED, store);
+    if closed {
+        closed_store.set(for_address.0.as_bytes(), &[1]);
+    } else {
+        closed_store.remove(for_address.0.as_bytes());
+    }
+}
+
+pub fn is_closed<S: ReadonlyStorage>(store: &S, for_address: &HumanAddr) -> bool {
+    ReadonlyPrefixedStorage::new(PREFIX_CLOSED, store).get(for_address.0.as_bytes()).is_some()
+}
+
+//removes whatever is stored under prefix for an address
+pub fn remove_entry<S: Storage>(store: &mut S, prefix: &[u8], for_address: &HumanAddr) {
+    PrefixedStorage::new(prefix, store).remove(for_address.0.as_bytes());
+}
+
+//generation messages are currently delivered to. Collections still in the original layout move into generation 0
+pub fn current_generation<S: ReadonlyStorage>(store: &S, owner: &HumanAddr) -> StdResult<u32> {
+    Ok(read_mailbox_header(store, owner)?.map_or(0, |header| header.generation))
+}
+
+// Lets grantee read the owner's messages matching filter, until the block height expires_at
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct ReadGrant {
+    pub expires_at: Option<u64>,
+    pub filter: MessageFilter,
+}
+
+impl ReadGrant {
+    pub fn is_expired(&self, height: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if height >= expires_at)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredReadGrant {
+    V1(ReadGrant),
+}
+
+impl From<StoredReadGrant> for ReadGrant {
+    fn from(stored: StoredReadGrant) -> Self {
+        match stored {
+            StoredReadGrant::V1(grant) => grant,
+        }
+    }
+}
+
+// ReadGrant as it was stored before being versioned, when filters could only narrow by sender and time. Bincode writes
+// a nested struct as its fields in a row, so the old filter's fields can sit directly in here
+#[derive(Serialize, Deserialize)]
+pub struct LegacyReadGrant {
+    pub expires_at: Option<u64>,
+    pub from_sender: Option<HumanAddr>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl From<LegacyReadGrant> for ReadGrant {
+    fn from(legacy: LegacyReadGrant) -> Self {
+        ReadGrant {
+            expires_at: legacy.expires_at,
+            filter: MessageFilter {
+                from_sender: legacy.from_sender,
+                since: legacy.since,
+                until: legacy.until,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+pub fn write_read_grant<S: Storage>(store: &mut S, owner: &HumanAddr, grantee: &HumanAddr, grant: &ReadGrant) -> StdResult<()> {
+    track_grantee(store, PREFIX_READ_GRANTS, owner, grantee, true)?;
+    PrefixedStorage::multilevel(&[PREFIX_LEGACY_READ_GRANTS, owner.0.as_bytes()], store).remove(grantee.0.as_bytes());
+    let mut grant_store = PrefixedStorage::multilevel(&[PREFIX_READ_GRANTS, owner.0.as_bytes()], store);
+    save(&mut grant_store, grantee.0.as_bytes(), &StoredReadGrant::V1(grant.clone()))
+}
+
+pub fn remove_read_grant<S: Storage>(store: &mut S, owner: &HumanAddr, grantee: &HumanAddr) -> StdResult<()> {
+    track_grantee(store, PREFIX_READ_GRANTS, owner, grantee, false)?;
+    PrefixedStorage::multilevel(&[PREFIX_LEGACY_READ_GRANTS, owner.0.as_bytes()], store).remove(grantee.0.as_bytes());
+    let mut grant_store = PrefixedStorage::multilevel(&[PREFIX_READ_GRANTS, owner.0.as_bytes()], store);
+    grant_store.remove(grantee.0.as_bytes());
+    Ok(())
+}
+
+/*Grants are keyed by grantee and storage can't be iterated, so each owner also keeps a list of who they granted what
+to, under [PREFIX_GRANTEES, grants_prefix]. That is what lets CloseMailbox find and remove them all*/
+pub fn read_grantees<S: ReadonlyStorage>(store: &S, grants_prefix: &[u8], owner: &HumanAddr) -> StdResult<Vec<HumanAddr>> {
+    let list_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_GRANTEES, grants_prefix], store);
+    match list_store.get(owner.0.as_bytes()) {
+        Some(bytes) => Bincode2::deserialize(&bytes),
+        None => Ok(vec![]),
+    }
+}
+
+fn track_grantee<S: Storage>(store: &mut S, grants_prefix: &[u8], owner: &HumanAddr, grantee: &HumanAddr, granted: bool) -> StdResult<()> {
+    let mut grantees = read_grantees(store, grants_prefix, owner)?;
+    let listed = grantees.contains(grantee);
+    if listed == granted {
+        return Ok(());
+    }
+    if granted {
+        grantees.push(grantee.clone());
+    } else {
+        grantees.retain(|listed| listed != grantee);
+    }
+
+    let mut list_store = PrefixedStorage::multilevel(&[PREFIX_GRANTEES, grants_prefix], store);
+    if grantees.is_empty() {
+        list_store.remove(owner.0.as_bytes());
+        return Ok(());
+    }
+    save(&mut list_store, owner.0.as_bytes(), &grantees)
+}
+
+// Lets a delegate send messages as the owner. allowance counts down with every message, None means unlimited
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Debug, Clone)]
+pub struct SendGrant {
+    pub allowance: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum StoredSendGrant {
+    V1(SendGrant),
+}
+
+impl From<StoredSendGrant> for SendGrant {
+    fn from(stored: StoredSendGrant) -> Self {
+        match stored {
+            StoredSendGrant::V1(grant) => grant,
+        }
+    }
+}
+
+// SendGrant as it was stored before being versioned, unversioned under PREFIX_LEGACY_SEND_GRANTS
+#[derive(Serialize, Deserialize)]
+pub struct LegacySendGrant {
+    pub allowance: Option<u32>,
+}
+
+impl From<LegacySendGrant> for SendGrant {
+    fn from(legacy: LegacySendGrant) -> Self {
+        SendGrant { allowance: legacy.allowance }
+    }
+}
+
+//the legacy entry goes once the grant is saved in the current layout
+pub fn write_send_grant<S: Storage>(store: &mut S, owner: &HumanAddr, delegate: &HumanAddr, grant: &SendGrant) -> StdResult<()> {
+    track_grantee(store, PREFIX_SEND_GRANTS, owner, delegate, true)?;
+    let mut grant_store = PrefixedStorage::multilevel(&[PREFIX_SEND_GRANTS, owner.0.as_bytes()], store);
+    save(&mut grant_store, delegate.0.as_bytes(), &StoredSendGrant::V1(grant.clone()))?;
+    PrefixedStorage::multilevel(&[PREFIX_LEGACY_SEND_GRANTS, owner.0.as_bytes()], store).remove(delegate.0.as_bytes());
+    Ok(())
+}
+
+pub fn remove_send_grant<S: Storage>(store: &mut S, owner: &HumanAddr, delegate: &HumanAddr) -> StdResult<()> {
+    track_grantee(store, PREFIX_SEND_GRANTS, owner, delegate, false)?;
+    PrefixedStorage::multilevel(&[PREFIX_SEND_GRANTS, owner.0.as_bytes()], store).remove(delegate.0.as_bytes());
+    PrefixedStorage::multilevel(&[PREFIX_LEGACY_SEND_GRANTS, owner.0.as_bytes()], store).remove(delegate.0.as_bytes());
+    Ok(())
+}
+
+pub fn read_send_grant<S: ReadonlyStorage>(store: &S, owner: &HumanAddr, delegate: &HumanAddr) -> StdResult<Option<SendGrant>> {
+    let grant_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_SEND_GRANTS, owner.0.as_bytes()], store);
+    if let Some(bytes) = grant_store.get(delegate.0.as_bytes()) {
+        return Ok(Some(Bincode2::deserialize::<StoredSendGrant>(&bytes)?.into()));
+    }
+    let legacy_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_LEGACY_SEND_GRANTS, owner.0.as_bytes()], store);
+    legacy_store
+        .get(delegate.0.as_bytes())
+        .map(|bytes| Bincode2::deserialize::<LegacySendGrant>(&bytes).map(SendGrant::from))
+        .transpose()
+}
+
+pub fn read_read_grant<S: ReadonlyStorage>(store: &S, owner: &HumanAddr, grantee: &HumanAddr) -> StdResult<Option<ReadGrant>> {
+    let grant_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_READ_GRANTS, owner.0.as_bytes()], store);
+    if let Some(bytes) = grant_store.get(grantee.0.as_bytes()) {
+        return Ok(Some(Bincode2::deserialize::<StoredReadGrant>(&bytes)?.into()));
+    }
+    let legacy_store = ReadonlyPrefixedStorage::multilevel(&[PREFIX_LEGACY_READ_GRANTS, owner.0.as_bytes()], store);
+    legacy_store
+        .get(grantee.0.as_bytes())
+        .map(|bytes| Ok(Bincode2::deserialize::<LegacyReadGrant>(&bytes)?.into()))
+        .transpose()
 }
 
 pub fn create_empty_collection<S: Storage> (
@@ -125,13 +1526,18 @@ pub fn create_empty_collection<S: Storage> (
     for_address: &HumanAddr,
 ) -> StdResult<HandleResponse>{
 
-    let mut store = PrefixedStorage::multilevel(
-        &[PREFIX_MSGS_RECEIVED, for_address.0.as_bytes()],
-        store
-    );
-    let _store = AppendStoreMut::<Message, _, _>::attach_or_create(&mut store)?;
+    write_mailbox_header(store, for_address, &empty_header(for_address))?;
     Ok(HandleResponse::default())
 }
+
+fn empty_header(for_address: &HumanAddr) -> MailboxHeader {
+    MailboxHeader {
+        owner: for_address.to_string(),
+        generation: 0,
+        compacted: 0,
+        sequence: 0,
+    }
+}
 /*
 
 Note from append_store.rs:
@@ -153,6 +1559,11 @@ unclear at the moment but preference is to keep the namespace long.
 attach_or_create function looked useful but for our purposes, I wanted to have more control over
 the list creation process using if-else statements - check out send_message and try_init for some context 
 
+The namespace now also ends with the generation of the collection. Whether a collection exists and who owns it
+is answered by the MailboxHeader instead of a placeholder message at index 0, so each generation starts out with 
+no AppendStore at all. append_message pushes through push_item rather than attach_or_create, so delivering to a new 
+mailbox does the same reads and writes as delivering to one that already has mail.
+
 pub const PREFIX_MSGS_SENT: &[u8] = b"messages_sent"; 
 Possibly, going to use this as a prefix for saving a collection of messages that user has sent to handle edge cases
 
@@ -4,13 +4,17 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 
-use cosmwasm_std::Env;
+use cosmwasm_std::{Env, StdError, StdResult};
 use secret_toolkit_crypto::{sha_256, Prng}; 
 
 use crate::utils::{create_hashed_password, ct_slice_compare};
 
 pub const VIEWING_KEY_SIZE: usize = 32;
 pub const VIEWING_KEY_PREFIX: &str = "anubis_key_";
+//rules for keys the user chooses themselves with SetViewingKey
+pub const MIN_VIEWING_KEY_LENGTH: usize = 20;
+pub const MIN_DISTINCT_CHARS: usize = 10;
+pub const MIN_CHAR_CLASSES: usize = 3;
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct ViewingKey(pub String);
@@ -22,7 +26,8 @@ impl ViewingKey {
         ct_slice_compare(&mine_hashed, hashed_pw)
     }
 
-    pub fn new(env: &Env, seed: &[u8], entropy: &[u8]) -> Self {
+    //returns the new key together with the seed to use for the next one
+    pub fn new(env: &Env, seed: &[u8], entropy: &[u8]) -> (Self, [u8; 32]) {
         // 16 here represents the lengths in bytes of the block height and time.
         let entropy_len = 16 + env.message.sender.len() + entropy.len();
         let mut rng_entropy = Vec::with_capacity(entropy_len);
@@ -37,7 +42,39 @@ impl ViewingKey {
 
         let key = sha_256(&rand_slice);
 
-        Self(VIEWING_KEY_PREFIX.to_string() + &base64::encode(key))
+        (Self(VIEWING_KEY_PREFIX.to_string() + &base64::encode(key)), rand_slice)
+    }
+
+    //a user-chosen key must be long, not too repetitive, and mix lowercase, uppercase, digits and symbols
+    pub fn check_strength(&self) -> StdResult<()> {
+        if self.0.chars().count() < MIN_VIEWING_KEY_LENGTH {
+            return Err(StdError::generic_err(format!(
+                "Viewing keys must be at least {} characters long", MIN_VIEWING_KEY_LENGTH
+            )));
+        }
+
+        let mut distinct: Vec<char> = self.0.chars().collect();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct.len() < MIN_DISTINCT_CHARS {
+            return Err(StdError::generic_err(format!(
+                "Viewing keys must use at least {} different characters", MIN_DISTINCT_CHARS
+            )));
+        }
+
+        let classes = [
+            distinct.iter().any(|c| c.is_lowercase()),
+            distinct.iter().any(|c| c.is_uppercase()),
+            distinct.iter().any(|c| c.is_numeric()),
+            distinct.iter().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|present| **present).count() < MIN_CHAR_CLASSES {
+            return Err(StdError::generic_err(format!(
+                "Viewing keys must mix at least {} of lowercase letters, uppercase letters, digits and symbols", MIN_CHAR_CLASSES
+            )));
+        }
+
+        Ok(())
     }
 
     pub fn to_hashed(&self) -> [u8; VIEWING_KEY_SIZE] {